@@ -0,0 +1,5 @@
+#![warn(missing_docs)]
+//! Circle physics playground. The simulation lives in `sim`, independent of the window;
+//! the `my_physics` binary is the notan front-end drawing and driving it.
+
+pub mod sim;
@@ -1,275 +1,2188 @@
+use my_physics::sim::{
+    circle_aabb, create_entity, default_obstacles, grid_cell_size, init_entities,
+    init_entities_grid, random_entity, random_velocity, reorder, Body, BoundaryMode, Broadphase,
+    BroadphaseKind, ContactEvent, Entities, Entity, Resolver, World, DENSITY_CELL_SIZE,
+    ENTITY_RADIUS, FOLLOW_STEERING_GAIN,
+};
 use notan::draw::*;
-use notan::math::{vec2, Vec2, Vec3};
+use notan::math::{vec2, Mat3, Rect, Vec2, Vec3};
 use notan::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
 
 const INITIAL_ENTITIES: usize = 40;
-const INITIAL_VELOCITY: f32 = 250.0;
-const ENTITY_RADIUS: f32 = 16.0;
+/// Seed for the scene generation, can be changed with `--seed <u64>`
+const SEED: u64 = 0xC1C1E;
+/// Distance between the centers of the entities of `--lattice`
+const LATTICE_SPACING: f32 = ENTITY_RADIUS * 2.0 + 4.0;
+/// Range of the random radius of the entities in the initial scene
+const INITIAL_MIN_RADIUS: f32 = 8.0;
+const INITIAL_MAX_RADIUS: f32 = 24.0;
+const MIN_SPAWN_RADIUS: f32 = 2.0;
+const MAX_SPAWN_RADIUS: f32 = 64.0;
+const SPAWN_RADIUS_STEP: f32 = 2.0;
+/// Entities added at once by the burst key
+const BURST_ENTITIES: usize = 1000;
+/// Speed the nudge keys add to every entity, see `World::apply_impulse`
+const NUDGE_SPEED: f32 = 300.0;
+/// Bodies in a cell of the density grid that stop the interactive spawns from adding more
+/// there, a new body in a fuller cell would start deep inside the others
+const SPAWN_MAX_DENSITY: u32 = 3;
+/// Seconds the HUD shows that spawns were skipped
+const SPAWN_SKIPPED_MESSAGE_TIME: f32 = 1.5;
+/// Speed of the entities spawned along a stroke, in the direction it was drawn
+const STROKE_SPEED: f32 = 150.0;
+/// Entities a stroke spawns per frame at most, the rest of a fast drag is skipped
+const MAX_STROKE_SPAWNS: usize = 10;
+/// Entities the interactive spawns can grow the scene to, past it they recycle the oldest
+/// ones instead
+const MAX_ENTITIES: usize = 20_000;
+/// Radius the mouse follower spawns with. Its mass comes from `mass_from_radius` like any
+/// other body, 16 times the mass of a default entity
+const BIG_CIRCLE_RADIUS: f32 = 64.0;
+/// Radius the big circle gains per second while the left button is held on empty space
+const BIG_CIRCLE_GROWTH: f32 = 40.0;
+const BIG_CIRCLE_MAX_RADIUS: f32 = 200.0;
 const GAME_WIDTH: f32 = 800.0;
 const GAME_HEIGHT: f32 = 600.0;
-const COLLISION_COLOR_TIME: f32 = 0.6;
-const ENTITY_COLOR: Color = Color::SILVER;
+
+/// File used by the save (F5) and load (F9) keys
+const SAVE_PATH: &str = "state.json";
+
+const SLOW_ENTITY_COLOR: Color = Color::BLUE;
+const FAST_ENTITY_COLOR: Color = Color::RED;
+/// Speed at which entities are drawn with the full `FAST_ENTITY_COLOR`
+const MAX_COLOR_SPEED: f32 = 500.0;
 const ENTITY_COLLISION_COLOR: Color = Color::ORANGE;
+const NO_COLLISIONS_COLOR: Color = Color::new(0.1, 0.1, 0.1, 1.0);
+const MANY_COLLISIONS_COLOR: Color = Color::WHITE;
+/// Contacts at which entities are drawn with the full `MANY_COLLISIONS_COLOR`,
+/// a circle packed among equal ones touches six
+const MAX_COLOR_COLLISIONS: u32 = 6;
+const OBSTACLE_COLOR: Color = Color::GRAY;
+/// Outline of the edges the bodies bounce against when `World::margin` insets them
+const BOUNDS_COLOR: Color = Color::GRAY;
+/// Images the entities can be drawn with, `Sprites::texture_ids` is always below it
+const TEXTURE_COUNT: usize = 3;
+/// Images the entities are drawn with, indexed by `Sprites::texture_ids`. They are white so
+/// the draw color tints them
+const ENTITY_TEXTURES: [&[u8]; TEXTURE_COUNT] = [
+    include_bytes!("../assets/white_circle.png"),
+    include_bytes!("../assets/white_ring.png"),
+    include_bytes!("../assets/white_notched.png"),
+];
+/// Draw size multiplier compensating the transparent padding of the entity textures,
+/// `1.0 / opaque_fraction`. They are 32x32 and their circles reach the edges (alpha 240 at
+/// the middle of each side of white_circle.png), so they need no correction.
+const TEXTURE_SCALE: f32 = 1.0;
+const DEBUG_AABB_COLOR: Color = Color::GREEN;
+const DEBUG_AABB_COLLIDING_COLOR: Color = Color::RED;
+const DEBUG_CONTACT_COLOR: Color = Color::MAGENTA;
+/// Length of the contact normals per unit of penetration, they are never shorter than
+/// `DEBUG_CONTACT_MIN_LENGTH` so touching contacts show too
+const DEBUG_CONTACT_SCALE: f32 = 4.0;
+const DEBUG_CONTACT_MIN_LENGTH: f32 = 4.0;
+const DENSITY_COLOR: Color = Color::YELLOW;
+/// Alpha of the densest cell, emptier cells fade to transparent
+const DENSITY_MAX_ALPHA: f32 = 0.6;
+const GRID_LINE_COLOR: Color = Color::GRAY;
+const GRID_LINE_ALPHA: f32 = 0.2;
+/// Tint of the grid cells, the fullest one gets `GRID_CELL_MAX_ALPHA`
+const GRID_CELL_COLOR: Color = Color::BLUE;
+const GRID_CELL_MAX_ALPHA: f32 = 0.35;
+const SELECTED_COLOR: Color = Color::MAGENTA;
+const SELECTION_BOX_COLOR: Color = Color::WHITE;
+/// Frames shown by the FPS graph
+const FPS_GRAPH_SAMPLES: usize = 240;
+const FPS_GRAPH_SIZE: Vec2 = vec2(240.0, 80.0);
+const FPS_GRAPH_COLOR: Color = Color::GREEN;
+const FPS_GRAPH_BACKGROUND: Color = Color::new(0.0, 0.0, 0.0, 0.5);
+/// Step times kept for the summary printed on exit, about 10 minutes at 60 fps
+const FRAME_TIME_SAMPLES: usize = 36_000;
+/// Positions kept per entity for the trails, one per frame
+const TRAIL_LENGTH: usize = 16;
+/// Alpha of the newest trail segment, older ones fade to zero
+const TRAIL_ALPHA: f32 = 0.5;
+/// Extra length of the motion blur per unit of speed, as a fraction of the diameter
+const MOTION_BLUR_SCALE: f32 = 0.004;
+const MAX_MOTION_BLUR_STRETCH: f32 = 3.0;
+/// Seconds `Sprites::collision_times` counts down from after a contact
+const COLLISION_COLOR_TIME: f32 = 0.6;
+/// Approach speed that makes a collision flash with the full intensity of 1.0
+const FULL_FLASH_SPEED: f32 = 500.0;
+/// Approach speed of a collision that throws particles
+const IMPACT_SPEED: f32 = 250.0;
+const PARTICLES_PER_IMPACT: usize = 6;
+/// Particles alive at once, impacts past it throw none
+const MAX_PARTICLES: usize = 2000;
+/// Seconds a particle lives, it fades out over them
+const PARTICLE_LIFETIME: f32 = 0.4;
+/// Top speed of the particles, they are thrown in random directions up to it
+const PARTICLE_SPEED: f32 = 200.0;
+const PARTICLE_SIZE: f32 = 3.0;
+/// Downward acceleration used when gravity is enabled
+const GRAVITY: Vec2 = vec2(0.0, 980.0);
+/// Strength of the cursor attractor, an acceleration of `strength / distance²`
+const ATTRACT_STRENGTH: f32 = 10_000_000.0;
+/// Friction used when toggled on, fraction of the sliding velocity removed per contact
+const FRICTION: f32 = 0.3;
+const MAX_RESOLVE_ITERATIONS: usize = 16;
+/// Duration of one physics step, the simulation always advances in these increments
+const FIXED_DELTA: f32 = 1.0 / 120.0;
+/// Steps allowed per frame before dropping time, avoids a spiral of death after a stall.
+/// Multiplied by the time scale when fast-forwarding
+const MAX_STEPS_PER_FRAME: usize = 8;
+/// Longest frame fed to the simulation and the camera, longer frames are slowed down
+const MAX_DELTA: f32 = 1.0 / 30.0;
+/// Simulation speeds the + and - keys go through, 0.0 stops the simulation
+const TIME_SCALES: [f32; 7] = [0.0, 0.1, 0.25, 0.5, 1.0, 2.0, 4.0];
+/// Lowest steering gain given to the followers marked with V, the highest is
+/// `FOLLOW_STEERING_GAIN`
+const FOLLOW_MIN_STEERING_GAIN: f32 = 1.0;
+/// Camera pan speed in screen pixels per second
+const CAMERA_PAN_SPEED: f32 = 400.0;
+/// Zoom multiplier applied per mouse wheel tick
+const CAMERA_ZOOM_STEP: f32 = 1.1;
+const CAMERA_MIN_ZOOM: f32 = 0.25;
+const CAMERA_MAX_ZOOM: f32 = 8.0;
+/// Radius multiplier applied to the grabbed entity per mouse wheel tick
+const GRAB_RESIZE_STEP: f32 = 1.1;
+const GRAB_MIN_RADIUS: f32 = 2.0;
+const GRAB_MAX_RADIUS: f32 = 160.0;
+/// Frames of cursor movement averaged for the speed of a thrown entity
+const CURSOR_HISTORY_SAMPLES: usize = 5;
+/// Top speed of a thrown entity, a flick can't send it flying through the walls
+const MAX_THROW_SPEED: f32 = 1500.0;
 
-#[derive(Copy, Clone, Debug)]
-struct Collision([usize; 2]);
-impl PartialEq for Collision {
-    fn eq(&self, other: &Self) -> bool {
-        let contain_id1 = self.0.contains(&other.0[0]);
-        let contain_id2 = self.0.contains(&other.0[1]);
-        contain_id1 && contain_id2
-    }
+/// What the color of the entities shows
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ColorMode {
+    /// Speed gradient, flashing on collisions
+    Speed,
+    /// How many bodies each entity overlaps, to spot where the resolver struggles
+    CollisionCount,
 }
 
-struct Body {
-    position: Vec2,
-    velocity: Vec2,
-    radius: f32,
+/// How the collision flash fades out
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum FadeCurve {
+    Linear,
+    /// Leaves the collision color fast and slows down towards the end
+    EaseOutQuad,
 }
 
+impl FadeCurve {
+    /// Eases `left`, the fraction of the flash still to go, from 1.0 when it starts to 0.0
+    fn ease(self, left: f32) -> f32 {
+        match self {
+            FadeCurve::Linear => left,
+            // 1 - (1 - elapsed)^2 on the elapsed fraction, written on what's left
+            FadeCurve::EaseOutQuad => left * left,
+        }
+    }
+}
+
+/// Where an entity is drawn, interpolated from its body every frame
+#[derive(Clone, Default)]
 struct Transform {
     position: Vec2,
     size: Vec2,
+    rotation: f32,
+}
+
+/// What the front-end keeps per entity to draw it, in the same order as `World::entities`.
+/// Every spawn, despawn and reorder of the world is mirrored here.
+#[derive(Default)]
+struct Sprites {
+    transforms: Vec<Transform>,
+    /// Index of the image each entity is drawn with, below `TEXTURE_COUNT`
+    texture_ids: Vec<usize>,
+    /// Seconds left of the collision flash
+    collision_times: Vec<f32>,
+    /// Strength of the flash, from the hardest hit since it started
+    collision_intensities: Vec<f32>,
+    trails: Vec<VecDeque<Vec2>>,
+}
+
+impl Sprites {
+    fn from_textures(texture_ids: impl IntoIterator<Item = usize>) -> Self {
+        let mut sprites = Sprites::default();
+        texture_ids
+            .into_iter()
+            .for_each(|texture_id| sprites.push(texture_id));
+        sprites
+    }
+
+    fn push(&mut self, texture_id: usize) {
+        self.transforms.push(Transform::default());
+        self.texture_ids.push(texture_id.min(TEXTURE_COUNT - 1));
+        self.collision_times.push(0.0);
+        self.collision_intensities.push(0.0);
+        self.trails.push(VecDeque::with_capacity(TRAIL_LENGTH));
+    }
+
+    /// Resets the sprite of a recycled entity, its trail would jump to the new position
+    fn replace(&mut self, idx: usize, texture_id: usize) {
+        self.texture_ids[idx] = texture_id.min(TEXTURE_COUNT - 1);
+        self.collision_times[idx] = 0.0;
+        self.collision_intensities[idx] = 0.0;
+        self.trails[idx].clear();
+    }
+
+    fn swap_remove(&mut self, idx: usize) {
+        self.transforms.swap_remove(idx);
+        self.texture_ids.swap_remove(idx);
+        self.collision_times.swap_remove(idx);
+        self.collision_intensities.swap_remove(idx);
+        self.trails.swap_remove(idx);
+    }
+
+    /// Same as `World::sort_by_morton` did to the entities, `order` holds the old ids
+    fn reorder(&mut self, order: &[usize]) {
+        reorder(&mut self.transforms, order);
+        reorder(&mut self.texture_ids, order);
+        reorder(&mut self.collision_times, order);
+        reorder(&mut self.collision_intensities, order);
+        reorder(&mut self.trails, order);
+    }
+}
+
+/// Texture for the entity with the given id, the ids cycle through all of them
+fn cycled_texture(id: usize) -> usize {
+    id % TEXTURE_COUNT
 }
 
-struct Entity {
-    body: Body,
-    transform: Transform,
-    is_colliding: bool,
-    collision_time: f32,
+/// Entity as written by `save_state`, with the image it's drawn with
+#[derive(Serialize, Deserialize)]
+struct SavedEntity {
+    #[serde(flatten)]
+    entity: Entity,
+    #[serde(default)]
+    texture_id: usize,
+}
+
+/// Spark thrown by an impact, only drawn, it's not part of the physics
+struct Particle {
+    position: Vec2,
+    velocity: Vec2,
+    time_left: f32,
+}
+
+/// CSV file the contacts of each frame are appended to, enabled with `--log-collisions`
+struct CollisionLog {
+    writer: BufWriter<File>,
+    frame: u64,
+}
+
+impl CollisionLog {
+    fn create(path: &str) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "frame,id1,id2,penetration").map_err(|e| e.to_string())?;
+        Ok(Self { writer, frame: 0 })
+    }
+
+    /// Writes and empties the contacts collected during this frame
+    fn write_frame(&mut self, events: &mut Vec<ContactEvent>) -> Result<(), String> {
+        let frame = self.frame;
+        self.frame += 1;
+        events
+            .drain(..)
+            .try_for_each(|ContactEvent { ids, penetration }| {
+                writeln!(
+                    self.writer,
+                    "{},{},{},{}",
+                    frame, ids[0], ids[1], penetration
+                )
+                .map_err(|e| e.to_string())
+            })
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.writer.flush().map_err(|e| e.to_string())
+    }
+}
+
+/// View over the world, `offset` is the world position at the top-left of the screen
+struct Camera {
+    offset: Vec2,
+    zoom: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            offset: Vec2::ZERO,
+            zoom: 1.0,
+        }
+    }
+}
+
+impl Camera {
+    fn screen_to_world(&self, point: Vec2) -> Vec2 {
+        point / self.zoom + self.offset
+    }
+
+    fn matrix(&self) -> Mat3 {
+        Mat3::from_scale(Vec2::splat(self.zoom)) * Mat3::from_translation(-self.offset)
+    }
+
+    /// Zooms keeping the world point under `anchor` (in screen space) in place
+    fn zoom_at(&mut self, anchor: Vec2, zoom: f32) {
+        let world_anchor = self.screen_to_world(anchor);
+        self.zoom = zoom.clamp(CAMERA_MIN_ZOOM, CAMERA_MAX_ZOOM);
+        self.offset = world_anchor - anchor / self.zoom;
+    }
+}
+
+/// Key of every action, `update` only checks keys through these so they can be changed
+/// in one place
+struct KeyBindings {
+    exit: KeyCode,
+    pause: KeyCode,
+    /// Single step while paused
+    step: KeyCode,
+    debug_draw: KeyCode,
+    density_draw: KeyCode,
+    grid_draw: KeyCode,
+    color_mode: KeyCode,
+    fade_curve: KeyCode,
+    morton_sort: KeyCode,
+    trails: KeyCode,
+    motion_blur: KeyCode,
+    gravity: KeyCode,
+    attractor: KeyCode,
+    avoid_density: KeyCode,
+    boundary: KeyCode,
+    friction: KeyCode,
+    sleep: KeyCode,
+    broadphase: KeyCode,
+    resolver: KeyCode,
+    fewer_iterations: KeyCode,
+    more_iterations: KeyCode,
+    smaller_radius: KeyCode,
+    bigger_radius: KeyCode,
+    /// Any of them changes the time scale, keyboards place `+` and `-` differently
+    faster: Vec<KeyCode>,
+    slower: Vec<KeyCode>,
+    /// Toggles the selected entities as mouse followers
+    followers: KeyCode,
+    burst: KeyCode,
+    reset: KeyCode,
+    regenerate: KeyCode,
+    clear: KeyCode,
+    save: KeyCode,
+    load: KeyCode,
+    pan_left: KeyCode,
+    pan_right: KeyCode,
+    pan_up: KeyCode,
+    pan_down: KeyCode,
+    /// Add `NUDGE_SPEED` to every entity in their direction
+    nudge_left: KeyCode,
+    nudge_right: KeyCode,
+    nudge_up: KeyCode,
+    nudge_down: KeyCode,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            exit: KeyCode::Escape,
+            pause: KeyCode::Space,
+            step: KeyCode::Period,
+            debug_draw: KeyCode::D,
+            density_draw: KeyCode::H,
+            grid_draw: KeyCode::X,
+            color_mode: KeyCode::K,
+            fade_curve: KeyCode::L,
+            morton_sort: KeyCode::M,
+            trails: KeyCode::T,
+            motion_blur: KeyCode::Z,
+            gravity: KeyCode::G,
+            attractor: KeyCode::A,
+            avoid_density: KeyCode::O,
+            boundary: KeyCode::W,
+            friction: KeyCode::F,
+            sleep: KeyCode::S,
+            broadphase: KeyCode::B,
+            resolver: KeyCode::I,
+            fewer_iterations: KeyCode::Y,
+            more_iterations: KeyCode::U,
+            smaller_radius: KeyCode::LBracket,
+            bigger_radius: KeyCode::RBracket,
+            faster: vec![KeyCode::Equals, KeyCode::Plus, KeyCode::Add],
+            slower: vec![KeyCode::Minus, KeyCode::Subtract],
+            followers: KeyCode::V,
+            burst: KeyCode::E,
+            reset: KeyCode::R,
+            regenerate: KeyCode::N,
+            clear: KeyCode::C,
+            save: KeyCode::F5,
+            load: KeyCode::F9,
+            pan_left: KeyCode::Left,
+            pan_right: KeyCode::Right,
+            pan_up: KeyCode::Up,
+            pan_down: KeyCode::Down,
+            nudge_left: KeyCode::Numpad4,
+            nudge_right: KeyCode::Numpad6,
+            nudge_up: KeyCode::Numpad8,
+            nudge_down: KeyCode::Numpad2,
+        }
+    }
 }
 
 #[derive(AppState)]
 struct State {
-    entities: Vec<Entity>,
-    texture: Texture,
+    world: World,
+    sprites: Sprites,
+    keys: KeyBindings,
+    /// One per `ENTITY_TEXTURES` entry
+    textures: Vec<Texture>,
+    font: Font,
+    seed: u64,
+    /// The window backend only applies vsync on creation, so it's chosen with `--vsync`
+    vsync: bool,
+    /// Prints every collision event to the console, set with `--print-collisions`
+    print_collisions: bool,
+    rng: Random,
+    camera: Camera,
+    /// Radius used for new entities
+    spawn_radius: f32,
     pause: bool,
+    /// Draws the broadphase boxes over the entities
+    debug_draw: bool,
+    /// Records and draws a fading trail behind each entity
+    trails: bool,
+    /// Stretches the drawn entities along their velocity
+    motion_blur: bool,
+    /// Sorts the entities in Z-order every frame before stepping
+    morton_sort: bool,
+    /// Draws how many bodies are in each cell of a coarse grid
+    density_draw: bool,
+    /// Draws the cells of the grid broadphase behind the entities
+    grid_draw: bool,
+    color_mode: ColorMode,
+    fade_curve: FadeCurve,
+    accumulator: f32,
+    /// Simulated seconds per real second
+    time_scale: f32,
+    /// Time spent running the physics steps during the last unpaused frame
+    last_step_ms: f32,
+    particles: Vec<Particle>,
+    /// Instant FPS of the last frames, newest at the back
+    fps_history: VecDeque<f32>,
+    /// Step time of the first `FRAME_TIME_SAMPLES` unpaused frames, in ms
+    frame_times: Vec<f32>,
+    /// Set with `--log-collisions`
+    collision_log: Option<CollisionLog>,
+    /// Set with `--threads`
+    thread_pool: Option<ThreadPool>,
+    /// File the entities are saved to on exit, set with `--autosave`
+    autosave: Option<String>,
+    kinetic_energy: f32,
+    momentum: f32,
+    /// Entity being dragged with the mouse
+    grabbed: Option<usize>,
+    /// Entity under the cursor at the end of the last update
+    hovered: Option<usize>,
+    /// Cursor positions of the last frames with the seconds since the one before them,
+    /// oldest first, see `cursor_velocity`
+    cursor_history: VecDeque<(Vec2, f32)>,
+    /// Corner where the selection drag started, while the button is held
+    selection_start: Option<Vec2>,
+    /// Last selection box, kept after releasing the button
+    selection: Option<Rect>,
+    /// Where the stroke being drawn spawned its last entity, while the button is held
+    stroke_last: Option<Vec2>,
+    /// The big circle grows while the button pressed on empty space is held
+    growing: bool,
+    /// Entities the interactive spawns stop growing the scene at, see `spawn_if_room`
+    max_entities: usize,
+    /// Interactive spawns refused by `spawn_if_room` since the message last went away
+    spawns_skipped: usize,
+    /// Seconds left showing `spawns_skipped` in the HUD
+    spawns_skipped_time: f32,
+    selected: Vec<usize>,
 }
 
 #[notan_main]
 fn main() -> Result<(), String> {
+    if let Some(steps) = arg_value("--headless").and_then(|value| value.parse().ok()) {
+        let count = arg_value("--entities")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(INITIAL_ENTITIES);
+        run_headless(
+            steps,
+            seed_arg(),
+            broadphase_arg(),
+            count,
+            has_arg("--morton"),
+        );
+        return Ok(());
+    }
+
+    let size = world_size_arg();
     let win = WindowConfig::default()
-        .set_size(GAME_WIDTH as _, GAME_HEIGHT as _)
-        .set_vsync(true);
+        .set_size(size.x as _, size.y as _)
+        .set_vsync(has_arg("--vsync"));
 
     notan::init_with(setup)
         .add_config(win)
         .add_config(DrawConfig)
         .update(update)
         .draw(draw)
+        .event(event)
         .build()
 }
 
-fn setup(gfx: &mut Graphics) -> State {
-    let entities = init_entities();
-    let texture = gfx
-        .create_texture()
-        .from_image(include_bytes!("../assets/white_circle.png"))
+/// Value following `name` in the command line arguments, like `--seed 42`
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args();
+    args.find(|arg| arg == name)?;
+    args.next()
+}
+
+/// True when `name` is passed as a flag, like `--vsync`
+fn has_arg(name: &str) -> bool {
+    std::env::args().any(|arg| arg == name)
+}
+
+fn seed_arg() -> u64 {
+    arg_value("--seed")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(SEED)
+}
+
+/// Size of the world, `--width` and `--height` default to the game size
+fn world_size_arg() -> Vec2 {
+    let value = |name: &str, default: f32| {
+        arg_value(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    vec2(value("--width", GAME_WIDTH), value("--height", GAME_HEIGHT))
+}
+
+fn resolver_arg() -> Resolver {
+    match arg_value("--resolver").as_deref() {
+        Some("islands") => Resolver::Islands,
+        Some("sequential") => Resolver::Sequential,
+        _ => Resolver::Jacobi,
+    }
+}
+
+/// Resolve passes per step, `--iterations <n>` clamped to `1..=MAX_RESOLVE_ITERATIONS`
+fn iterations_arg() -> usize {
+    arg_value("--iterations")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(1)
+        .clamp(1, MAX_RESOLVE_ITERATIONS)
+}
+
+fn boundary_arg() -> BoundaryMode {
+    match arg_value("--boundary").as_deref() {
+        Some("walls") => BoundaryMode::Walls,
+        Some("wrap") => BoundaryMode::Wrap,
+        _ => BoundaryMode::Bounce,
+    }
+}
+
+/// Pool for the simulation with `--threads <n>` threads, an error building it is reported
+/// and the global pool is used instead
+fn thread_pool_arg() -> Option<ThreadPool> {
+    let threads: usize = arg_value("--threads")?.parse().ok()?;
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
         .build()
+        .map_err(|e| eprintln!("Error creating a pool of {} threads: {}", threads, e))
+        .ok()
+}
+
+/// Columns and rows of `--lattice <cols>x<rows>`, the scene starts as that lattice
+/// instead of the random one
+fn lattice_arg() -> Option<(usize, usize)> {
+    let value = arg_value("--lattice")?;
+    let (cols, rows) = value.split_once('x')?;
+    Some((cols.parse().ok()?, rows.parse().ok()?))
+}
+
+/// Log opened with `--log-collisions <path>`, an error opening it is reported and ignored
+fn collision_log_arg() -> Option<CollisionLog> {
+    let path = arg_value("--log-collisions")?;
+    CollisionLog::create(&path)
+        .map_err(|e| eprintln!("Error creating '{}': {}", path, e))
+        .ok()
+}
+
+/// Broadphase used by the headless mode, `--broadphase brute|grid|quadtree|two-tier`
+fn broadphase_arg() -> BroadphaseKind {
+    match arg_value("--broadphase").as_deref() {
+        Some("brute") => BroadphaseKind::BruteForce,
+        Some("quadtree") => BroadphaseKind::Quadtree,
+        Some("two-tier") => BroadphaseKind::TwoTier,
+        _ => BroadphaseKind::Grid,
+    }
+}
+
+/// Steps the simulation without a window and prints the average step time
+fn run_headless(
+    steps: usize,
+    seed: u64,
+    broadphase: BroadphaseKind,
+    count: usize,
+    morton_sort: bool,
+) {
+    let mut rng = Random::new(seed);
+    let size = world_size_arg();
+    let mut entities = match lattice_arg() {
+        Some((cols, rows)) => {
+            init_entities_grid(cols, rows, LATTICE_SPACING, size, &default_obstacles())
+        }
+        None => init_entities(
+            &mut rng,
+            count,
+            INITIAL_MIN_RADIUS,
+            INITIAL_MAX_RADIUS,
+            size,
+        ),
+    };
+    // `--big-circle` adds the one of the window scene, without a cursor it doesn't steer
+    if has_arg("--big-circle") {
+        entities.push(big_circle(size * 0.5));
+    }
+    let mut world = World::new(entities, grid_cell_size(INITIAL_MAX_RADIUS), size);
+    world.broadphase.kind = broadphase;
+    world.boundary = boundary_arg();
+    world.resolver = resolver_arg();
+    world.resolve_iterations = iterations_arg();
+    world.sleep = has_arg("--sleep");
+    let mut collision_log = collision_log_arg();
+    world.contact_events = collision_log.as_ref().map(|_| vec![]);
+    let pool = thread_pool_arg();
+
+    // `--verify-broadphase` compares the pairs found after every step with checking all of
+    // them, the time spent on it is left out of the average
+    let verify = has_arg("--verify-broadphase");
+    let mut mismatched_steps = 0;
+    let mut candidate_pairs = 0;
+    let mut reset_count = 0;
+    let mut elapsed = Duration::ZERO;
+    (0..steps).for_each(|_| {
+        let start = Instant::now();
+        if morton_sort {
+            world.sort_by_morton();
+        }
+        simulate_on(pool.as_ref(), &mut world, FIXED_DELTA);
+        write_collision_log(&mut collision_log, &mut world.contact_events);
+        elapsed += start.elapsed();
+        candidate_pairs += world.stats.candidate_pairs;
+        reset_count += world.reset_count;
+
+        if verify && !world.check_broadphase() {
+            mismatched_steps += 1;
+        }
+    });
+    let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+
+    println!(
+        "{} steps with {} entities ({:?}{}, {} threads), average step: {:.4}ms, last step pairs: {} collisions / {} candidates, average candidates: {:.1}, stuck: {}",
+        steps,
+        world.entities.len(),
+        broadphase,
+        if morton_sort { ", morton sorted" } else { "" },
+        thread_count(pool.as_ref()),
+        elapsed_ms / steps.max(1) as f64,
+        world.stats.collision_pairs,
+        world.stats.candidate_pairs,
+        candidate_pairs as f64 / steps.max(1) as f64,
+        world.entities.stuck_count()
+    );
+    println!("State hash: {:016x}", world.state_hash());
+    if reset_count > 0 {
+        println!(
+            "Reset {} bodies with non-finite values or far outside the world",
+            reset_count
+        );
+    }
+    if verify {
+        println!(
+            "Broadphase verified against all pairs: {} of {} steps mismatched",
+            mismatched_steps, steps
+        );
+    }
+}
+
+fn setup(gfx: &mut Graphics) -> State {
+    let seed = seed_arg();
+    let mut rng = Random::new(seed);
+    let size = world_size_arg();
+    let (entities, sprites) = initial_entities(&mut rng, size);
+    let textures = ENTITY_TEXTURES
+        .iter()
+        .map(|bytes| gfx.create_texture().from_image(bytes).build().unwrap())
+        .collect();
+    let font = gfx
+        .create_font(include_bytes!("../assets/Ubuntu-B.ttf"))
         .unwrap();
+    let collision_log = collision_log_arg();
     State {
-        entities,
+        world: World {
+            boundary: boundary_arg(),
+            resolver: resolver_arg(),
+            resolve_iterations: iterations_arg(),
+            sleep: has_arg("--sleep"),
+            contact_events: collision_log.as_ref().map(|_| vec![]),
+            collision_events: Some(vec![]),
+            ..World::new(entities, grid_cell_size(INITIAL_MAX_RADIUS), size)
+        },
+        sprites,
         pause: false,
-        texture,
+        keys: KeyBindings::default(),
+        textures,
+        font,
+        seed,
+        vsync: has_arg("--vsync"),
+        print_collisions: has_arg("--print-collisions"),
+        rng,
+        camera: Camera::default(),
+        spawn_radius: ENTITY_RADIUS,
+        debug_draw: false,
+        trails: false,
+        motion_blur: false,
+        morton_sort: false,
+        density_draw: false,
+        grid_draw: false,
+        color_mode: ColorMode::Speed,
+        fade_curve: FadeCurve::Linear,
+        accumulator: 0.0,
+        time_scale: 1.0,
+        last_step_ms: 0.0,
+        particles: vec![],
+        fps_history: VecDeque::with_capacity(FPS_GRAPH_SAMPLES),
+        frame_times: Vec::with_capacity(FRAME_TIME_SAMPLES),
+        collision_log,
+        thread_pool: thread_pool_arg(),
+        autosave: arg_value("--autosave"),
+        kinetic_energy: 0.0,
+        momentum: 0.0,
+        grabbed: None,
+        hovered: None,
+        cursor_history: VecDeque::with_capacity(CURSOR_HISTORY_SAMPLES),
+        selection_start: None,
+        stroke_last: None,
+        growing: false,
+        max_entities: MAX_ENTITIES,
+        spawns_skipped: 0,
+        spawns_skipped_time: 0.0,
+        selection: None,
+        selected: vec![],
     }
 }
 
 fn update(app: &mut App, state: &mut State) {
-    if app.keyboard.was_pressed(KeyCode::Space) {
+    // the app closes at the end of the frame, `event` gets `Event::Exit` before that
+    if app.keyboard.was_pressed(state.keys.exit) {
+        app.exit();
+    }
+
+    if app.keyboard.was_pressed(state.keys.pause) {
         state.pause = !state.pause;
     }
 
-    if state.pause {
-        return;
+    if app.keyboard.was_pressed(state.keys.debug_draw) {
+        state.debug_draw = !state.debug_draw;
+        state.world.contact_points = state.debug_draw.then(Vec::new);
+    }
+
+    if app.keyboard.was_pressed(state.keys.density_draw) {
+        state.density_draw = !state.density_draw;
+    }
+
+    if app.keyboard.was_pressed(state.keys.grid_draw) {
+        state.grid_draw = !state.grid_draw;
+    }
+
+    if app.keyboard.was_pressed(state.keys.color_mode) {
+        state.color_mode = match state.color_mode {
+            ColorMode::Speed => ColorMode::CollisionCount,
+            ColorMode::CollisionCount => ColorMode::Speed,
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.fade_curve) {
+        state.fade_curve = match state.fade_curve {
+            FadeCurve::Linear => FadeCurve::EaseOutQuad,
+            FadeCurve::EaseOutQuad => FadeCurve::Linear,
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.morton_sort) {
+        state.morton_sort = !state.morton_sort;
+    }
+
+    if app.keyboard.was_pressed(state.keys.trails) {
+        state.trails = !state.trails;
+        state.sprites.trails.iter_mut().for_each(VecDeque::clear);
+    }
+
+    if app.keyboard.was_pressed(state.keys.motion_blur) {
+        state.motion_blur = !state.motion_blur;
+    }
+
+    if app.keyboard.was_pressed(state.keys.gravity) {
+        state.world.gravity = if state.world.gravity == Vec2::ZERO {
+            GRAVITY
+        } else {
+            Vec2::ZERO
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.attractor) {
+        state.world.attract_strength = if state.world.attract_strength == 0.0 {
+            ATTRACT_STRENGTH
+        } else {
+            0.0
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.avoid_density) {
+        state.world.avoid_density = !state.world.avoid_density;
+    }
+
+    if app.keyboard.was_pressed(state.keys.boundary) {
+        state.world.boundary = match state.world.boundary {
+            BoundaryMode::Bounce => BoundaryMode::Walls,
+            BoundaryMode::Walls => BoundaryMode::Wrap,
+            BoundaryMode::Wrap => BoundaryMode::Bounce,
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.friction) {
+        state.world.friction = if state.world.friction == 0.0 {
+            FRICTION
+        } else {
+            0.0
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.sleep) {
+        state.world.sleep = !state.world.sleep;
+    }
+
+    if app.keyboard.was_pressed(state.keys.broadphase) {
+        state.world.broadphase.kind = match state.world.broadphase.kind {
+            BroadphaseKind::BruteForce => BroadphaseKind::Grid,
+            BroadphaseKind::Grid => BroadphaseKind::Quadtree,
+            BroadphaseKind::Quadtree => BroadphaseKind::TwoTier,
+            BroadphaseKind::TwoTier => BroadphaseKind::BruteForce,
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.resolver) {
+        state.world.resolver = match state.world.resolver {
+            Resolver::Jacobi => Resolver::Islands,
+            Resolver::Islands => Resolver::Sequential,
+            Resolver::Sequential => Resolver::Jacobi,
+        };
+    }
+
+    if app.keyboard.was_pressed(state.keys.fewer_iterations) {
+        state.world.resolve_iterations = state.world.resolve_iterations.saturating_sub(1).max(1);
+    }
+
+    if app.keyboard.was_pressed(state.keys.more_iterations) {
+        state.world.resolve_iterations =
+            (state.world.resolve_iterations + 1).min(MAX_RESOLVE_ITERATIONS);
+    }
+
+    if app.keyboard.was_pressed(state.keys.smaller_radius) {
+        state.spawn_radius = (state.spawn_radius - SPAWN_RADIUS_STEP).max(MIN_SPAWN_RADIUS);
+    }
+
+    if app.keyboard.was_pressed(state.keys.bigger_radius) {
+        state.spawn_radius = (state.spawn_radius + SPAWN_RADIUS_STEP).min(MAX_SPAWN_RADIUS);
+    }
+
+    if state
+        .keys
+        .faster
+        .iter()
+        .any(|&key| app.keyboard.was_pressed(key))
+    {
+        state.time_scale = TIME_SCALES
+            .into_iter()
+            .find(|&scale| scale > state.time_scale)
+            .unwrap_or(state.time_scale);
+    }
+
+    if state
+        .keys
+        .slower
+        .iter()
+        .any(|&key| app.keyboard.was_pressed(key))
+    {
+        state.time_scale = TIME_SCALES
+            .into_iter()
+            .rev()
+            .find(|&scale| scale < state.time_scale)
+            .unwrap_or(state.time_scale);
+    }
+
+    if app.keyboard.was_pressed(state.keys.followers) {
+        toggle_selected_followers(state);
+    }
+
+    if app.keyboard.was_pressed(state.keys.burst) {
+        spawn_burst(state);
+    }
+
+    let nudge = [
+        (state.keys.nudge_left, -Vec2::X),
+        (state.keys.nudge_right, Vec2::X),
+        (state.keys.nudge_up, -Vec2::Y),
+        (state.keys.nudge_down, Vec2::Y),
+    ]
+    .into_iter()
+    .filter(|&(key, _)| app.keyboard.was_pressed(key))
+    .map(|(_, direction)| direction)
+    .sum::<Vec2>();
+    if nudge != Vec2::ZERO {
+        state.world.apply_impulse(nudge * NUDGE_SPEED);
+    }
+
+    if app.keyboard.was_pressed(state.keys.reset) {
+        reset_scene(state);
+    }
+
+    if app.keyboard.was_pressed(state.keys.regenerate) {
+        regenerate_scene(state);
+    }
+
+    // despawning happens before the systems run so no collision ids are stale
+    if app.keyboard.was_pressed(state.keys.clear) {
+        despawn_all(state);
+    }
+
+    if app.keyboard.was_pressed(state.keys.save) {
+        if let Err(e) = save_state(state, SAVE_PATH) {
+            eprintln!("Error saving to '{}': {}", SAVE_PATH, e);
+        }
+    }
+
+    if app.keyboard.was_pressed(state.keys.load) {
+        match load_state(SAVE_PATH) {
+            Ok(saved) => {
+                state.sprites = Sprites::from_textures(saved.iter().map(|s| s.texture_id));
+                state.world.entities = saved.into_iter().map(|s| s.entity).collect();
+                state.grabbed = None;
+                clear_selection(state);
+            }
+            Err(e) => eprintln!("Error loading '{}': {}", SAVE_PATH, e),
+        }
+    }
+
+    // after a stall (window dragged, app suspended) the time lost is dropped, not simulated
+    let real_delta = app.timer.delta_f32();
+    let frame_delta = real_delta.min(MAX_DELTA);
+    if real_delta > 0.0 {
+        if state.fps_history.len() == FPS_GRAPH_SAMPLES {
+            state.fps_history.pop_front();
+        }
+        state.fps_history.push_back(1.0 / real_delta);
+    }
+    if state.spawns_skipped_time > 0.0 {
+        state.spawns_skipped_time -= real_delta;
+        if state.spawns_skipped_time <= 0.0 {
+            state.spawns_skipped = 0;
+        }
+    }
+    // while an entity is grabbed the wheel resizes it instead of zooming
+    update_camera(
+        app,
+        &state.keys,
+        &mut state.camera,
+        frame_delta,
+        state.grabbed.is_none(),
+    );
+
+    let (x, y) = app.mouse.position();
+    let cursor = state.camera.screen_to_world(vec2(x, y));
+    if state.cursor_history.len() == CURSOR_HISTORY_SAMPLES {
+        state.cursor_history.pop_front();
+    }
+    state.cursor_history.push_back((cursor, real_delta));
+    state.world.follow_target = Some(cursor);
+
+    if app.mouse.right_was_pressed() {
+        spawn_small_at_cursor(state, cursor);
+    }
+
+    // shift + drag selects, a click without dragging clears the selection,
+    // ctrl + drag spawns entities along the path of the cursor, holding the button on
+    // empty space grows the big circle
+    let shift = app.keyboard.shift();
+    if app.mouse.left_was_pressed() {
+        if shift {
+            state.selection_start = Some(cursor);
+        } else if app.keyboard.ctrl() {
+            state.stroke_last = Some(cursor);
+        } else {
+            state.grabbed = state.world.pick_entity(cursor);
+            state.growing = state.grabbed.is_none();
+        }
+    }
+
+    if let Some(start) = state.selection_start {
+        let min = start.min(cursor);
+        let max = start.max(cursor);
+        state.selected = state.world.query_region(min, max);
+        state.selection = Some(Rect {
+            x: min.x,
+            y: min.y,
+            width: max.x - min.x,
+            height: max.y - min.y,
+        });
+        if !app.mouse.left_is_down() {
+            state.selection_start = None;
+            if min == max {
+                clear_selection(state);
+            }
+        }
+    }
+
+    if let Some(last) = state.stroke_last {
+        state.stroke_last = app
+            .mouse
+            .left_is_down()
+            .then(|| spawn_along_stroke(state, last, cursor));
+    }
+
+    let wheel = app.mouse.wheel_delta.y;
+    if let (Some(id), true) = (state.grabbed, wheel != 0.0) {
+        let factor = if wheel > 0.0 {
+            GRAB_RESIZE_STEP
+        } else {
+            1.0 / GRAB_RESIZE_STEP
+        };
+        let radius = state.world.entities.radii[id] * factor;
+        state
+            .world
+            .resize(id, radius.clamp(GRAB_MIN_RADIUS, GRAB_MAX_RADIUS));
+    }
+
+    state.growing &= app.mouse.left_is_down();
+    if state.growing && !state.pause {
+        grow_big_circle(
+            &mut state.world,
+            BIG_CIRCLE_GROWTH * frame_delta * state.time_scale,
+        );
+    }
+
+    if let Some(id) = state.grabbed {
+        let entities = &mut state.world.entities;
+        entities.bodies[id].wake();
+        if app.mouse.left_is_down() {
+            entities.positions[id] = cursor;
+            entities.bodies[id].last_position = cursor;
+            entities.velocities[id] = Vec2::ZERO;
+        } else {
+            // throw it with the speed the cursor had over the last frames
+            entities.velocities[id] =
+                cursor_velocity(&state.cursor_history).clamp_length_max(MAX_THROW_SPEED);
+            state.grabbed = None;
+        }
     }
 
     // -- logic
-    let delta = app.timer.delta_f32();
+    let mut simulated = 0.0;
+    if !state.pause {
+        sort_entities(state);
+        let start = Instant::now();
+        let steps = fixed_steps(&mut state.accumulator, real_delta, state.time_scale);
+        for _ in 0..steps {
+            simulate_on(state.thread_pool.as_ref(), &mut state.world, FIXED_DELTA);
+            warn_reset_bodies(&state.world);
+        }
+        simulated = steps as f32 * FIXED_DELTA;
+        state.last_step_ms = start.elapsed().as_secs_f32() * 1000.0;
+        if state.frame_times.len() < FRAME_TIME_SAMPLES {
+            state.frame_times.push(state.last_step_ms);
+        }
+    } else if app.keyboard.was_pressed(state.keys.step) {
+        // one fixed step regardless of the frame time, so stepping is reproducible
+        sort_entities(state);
+        simulate_on(state.thread_pool.as_ref(), &mut state.world, FIXED_DELTA);
+        warn_reset_bodies(&state.world);
+        simulated = FIXED_DELTA;
+    }
+    write_collision_log(&mut state.collision_log, &mut state.world.contact_events);
+    process_events(state, simulated);
+    if !state.pause {
+        sys_update_particles(&mut state.particles, frame_delta * state.time_scale);
+    }
+
+    (state.kinetic_energy, state.momentum) = state.world.energy();
+
+    // synced even when paused so dragged entities are drawn where they are, without
+    // interpolating so a single step shows its exact result
+    let alpha = if state.pause {
+        1.0
+    } else {
+        state.accumulator / FIXED_DELTA
+    };
+    let entities = &state.world.entities;
+    sys_body_to_transform(
+        &entities.positions,
+        &entities.radii,
+        &entities.bodies,
+        &mut state.sprites.transforms,
+        alpha,
+    );
+
+    if state.density_draw {
+        state.world.update_density();
+    }
+
+    state.hovered = state.world.pick_entity(cursor);
+
+    if state.trails && !state.pause {
+        sys_record_trails(&state.sprites.transforms, &mut state.sprites.trails);
+    }
+}
+
+/// Consumes the collision events of the frame, the ids are still valid since the sort
+/// only runs before stepping. `simulated` is the time stepped this frame, the flashes fade
+/// by it before the new collisions start theirs. Hits harder than `IMPACT_SPEED` throw
+/// particles and every event is printed with `--print-collisions`.
+fn process_events(state: &mut State, simulated: f32) {
+    let sprites = &mut state.sprites;
+    if simulated > 0.0 {
+        sys_fade_flashes(
+            &mut sprites.collision_times,
+            &mut sprites.collision_intensities,
+            simulated,
+        );
+        sprites
+            .collision_times
+            .iter_mut()
+            .zip(&state.world.entities.is_colliding)
+            .filter(|(_, &is_colliding)| is_colliding)
+            .for_each(|(time, _)| *time = COLLISION_COLOR_TIME);
+    }
+    let Some(events) = state.world.collision_events.as_mut() else {
+        return;
+    };
+    events.drain(..).for_each(|event| {
+        // the flash keeps the hardest hit until it fades out
+        let intensity = (event.impact_speed / FULL_FLASH_SPEED).min(1.0);
+        [event.id1, event.id2].into_iter().for_each(|id| {
+            sprites.collision_times[id] = COLLISION_COLOR_TIME;
+            sprites.collision_intensities[id] = sprites.collision_intensities[id].max(intensity);
+        });
+        if state.print_collisions {
+            println!(
+                "Collision {} {}, speed {:.1} at ({:.1}, {:.1})",
+                event.id1, event.id2, event.impact_speed, event.position.x, event.position.y
+            );
+        }
+        if event.impact_speed > IMPACT_SPEED {
+            spawn_impact_particles(&mut state.particles, event.position, &mut state.rng);
+        }
+    });
+}
+
+/// Throws particles from the point unless there are too many already
+fn spawn_impact_particles(particles: &mut Vec<Particle>, point: Vec2, rng: &mut Random) {
+    if particles.len() + PARTICLES_PER_IMPACT > MAX_PARTICLES {
+        return;
+    }
+    particles.extend((0..PARTICLES_PER_IMPACT).map(|_| Particle {
+        position: point,
+        velocity: Vec2::from_angle(rng.gen::<f32>() * TAU) * rng.gen::<f32>() * PARTICLE_SPEED,
+        time_left: PARTICLE_LIFETIME,
+    }));
+}
+
+/// Sorts the entities in Z-order if enabled, ids are only valid until the next sort so it
+/// runs before stepping
+fn sort_entities(state: &mut State) {
+    if state.morton_sort {
+        let order = state.world.sort_by_morton();
+        remap_ids(state, &order);
+    }
+}
+
+fn event(state: &mut State, evt: Event) {
+    if let Event::Exit = evt {
+        print_frame_times(&mut state.frame_times);
+        if let Some(Err(e)) = state.collision_log.as_mut().map(CollisionLog::flush) {
+            eprintln!("Error writing the collision log: {}", e);
+        }
+        if let Some(path) = &state.autosave {
+            match save_state(state, path) {
+                Ok(()) => println!(
+                    "Saved {} entities to '{}'",
+                    state.world.entities.len(),
+                    path
+                ),
+                Err(e) => eprintln!("Error saving to '{}': {}", path, e),
+            }
+        }
+    }
+}
+
+/// Appends the contacts of this frame to the log, logging stops after an error
+fn write_collision_log(
+    log: &mut Option<CollisionLog>,
+    contact_events: &mut Option<Vec<ContactEvent>>,
+) {
+    let (Some(writer), Some(events)) = (log.as_mut(), contact_events.as_mut()) else {
+        return;
+    };
+    if let Err(e) = writer.write_frame(events) {
+        eprintln!("Error writing the collision log: {}", e);
+        *log = None;
+        *contact_events = None;
+    }
+}
+
+/// Prints the percentiles of the recorded step times
+fn print_frame_times(frame_times: &mut [f32]) {
+    if frame_times.is_empty() {
+        return;
+    }
 
-    sys_clean_collisions(&mut state.entities, delta);
-    sys_apply_velocity_to_body(&mut state.entities, delta);
-    sys_bounce_rect(&mut state.entities);
-    let collisions = sys_check_collision(&mut state.entities);
-    sys_resolve_collisions(&mut state.entities, collisions);
-    sys_body_to_transform(&mut state.entities);
+    frame_times.sort_unstable_by(f32::total_cmp);
+    let percentile = |p: f32| frame_times[((frame_times.len() - 1) as f32 * p).round() as usize];
+    println!(
+        "Step time over {} frames: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+        frame_times.len(),
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0),
+    );
 }
 
-fn draw(gfx: &mut Graphics, state: &mut State) {
+fn update_camera(app: &App, keys: &KeyBindings, camera: &mut Camera, delta: f32, wheel_zoom: bool) {
+    let mut direction = Vec2::ZERO;
+    if app.keyboard.is_down(keys.pan_left) {
+        direction.x -= 1.0;
+    }
+    if app.keyboard.is_down(keys.pan_right) {
+        direction.x += 1.0;
+    }
+    if app.keyboard.is_down(keys.pan_up) {
+        direction.y -= 1.0;
+    }
+    if app.keyboard.is_down(keys.pan_down) {
+        direction.y += 1.0;
+    }
+    camera.offset += direction * CAMERA_PAN_SPEED * delta / camera.zoom;
+
+    let wheel = app.mouse.wheel_delta.y;
+    if wheel_zoom && wheel != 0.0 {
+        let factor = if wheel > 0.0 {
+            CAMERA_ZOOM_STEP
+        } else {
+            1.0 / CAMERA_ZOOM_STEP
+        };
+        let (x, y) = app.mouse.position();
+        camera.zoom_at(vec2(x, y), camera.zoom * factor);
+    }
+}
+
+/// Adds the frame time to the accumulator and returns how many `FIXED_DELTA` steps to run.
+/// The steps are still FIXED_DELTA long, the scale changes how many run per frame so
+/// everything driven by the step delta, like the collision flashes, scales with it.
+/// Past the steps allowed per frame the rest of the accumulator is dropped
+fn fixed_steps(accumulator: &mut f32, real_delta: f32, time_scale: f32) -> usize {
+    *accumulator += real_delta.min(MAX_DELTA) * time_scale;
+    let max_steps = (MAX_STEPS_PER_FRAME as f32 * time_scale.max(1.0)).ceil() as usize;
+    let mut steps = 0;
+    while *accumulator >= FIXED_DELTA {
+        if steps == max_steps {
+            *accumulator = 0.0;
+            break;
+        }
+
+        *accumulator -= FIXED_DELTA;
+        steps += 1;
+    }
+    steps
+}
+
+/// Runs `World::step` inside the `--threads` pool, or on the global rayon pool without one
+fn simulate_on(pool: Option<&ThreadPool>, world: &mut World, delta: f32) {
+    match pool {
+        Some(pool) => pool.install(|| world.step(delta)),
+        None => world.step(delta),
+    }
+}
+
+/// Reports the bodies the last step had to put back, a sign the solver blew up
+fn warn_reset_bodies(world: &World) {
+    if world.reset_count > 0 {
+        eprintln!(
+            "Warning: reset {} bodies with non-finite values or far outside the world",
+            world.reset_count
+        );
+    }
+}
+
+/// Threads the parallel systems of `simulate_on` use
+fn thread_count(pool: Option<&ThreadPool>) -> usize {
+    pool.map_or_else(rayon::current_num_threads, ThreadPool::current_num_threads)
+}
+
+fn draw(app: &mut App, gfx: &mut Graphics, state: &mut State) {
     let mut draw = gfx.create_draw();
     draw.clear(Color::BLACK);
+    draw.transform().push(state.camera.matrix());
+
+    if state.grid_draw {
+        draw_broadphase_grid(&mut draw, &state.world.broadphase);
+    }
 
-    state.entities.iter().for_each(|e| {
-        let pos = e.transform.position - e.transform.size * 0.5;
-        let color = if e.collision_time > 0.0 {
-            interpolate_color(
-                ENTITY_COLOR,
-                ENTITY_COLLISION_COLOR,
-                COLLISION_COLOR_TIME,
-                e.collision_time,
+    let entities = &state.world.entities;
+    let sprites = &state.sprites;
+    if state.trails {
+        sprites
+            .trails
+            .iter()
+            .zip(sprites.transforms.iter())
+            .zip(
+                entities
+                    .velocities
+                    .iter()
+                    .zip(entities.collision_counts.iter()),
             )
-        } else {
-            ENTITY_COLOR
-        };
-        draw.image(&state.texture)
+            .for_each(|((trail, transform), (velocity, &count))| {
+                let color = match state.color_mode {
+                    ColorMode::Speed => speed_to_color(velocity.length()),
+                    ColorMode::CollisionCount => collision_count_to_color(count),
+                };
+                let width = (transform.size.x * 0.25).max(1.0);
+                let segments = trail.len().saturating_sub(1);
+                trail
+                    .iter()
+                    .zip(trail.iter().skip(1))
+                    .enumerate()
+                    .for_each(|(i, (from, to))| {
+                        let age = (i + 1) as f32 / segments as f32;
+                        draw.line((from.x, from.y), (to.x, to.y))
+                            .width(width)
+                            .color(color)
+                            .alpha(TRAIL_ALPHA * age);
+                    });
+            });
+    }
+
+    // notan already merges consecutive images with the same texture into one batch and
+    // has no instancing to go further, drawing the entities grouped by texture keeps it
+    // to one draw call per texture
+    state
+        .textures
+        .iter()
+        .enumerate()
+        .for_each(|(texture_id, texture)| {
+            sprites
+                .transforms
+                .iter()
+                .zip(
+                    entities
+                        .velocities
+                        .iter()
+                        .zip(entities.collision_counts.iter()),
+                )
+                .zip(
+                    sprites
+                        .collision_times
+                        .iter()
+                        .zip(sprites.collision_intensities.iter()),
+                )
+                .zip(sprites.texture_ids.iter())
+                .filter(|(_, &id)| id == texture_id)
+                .for_each(
+                    |(((transform, (&velocity, &count)), (&collision_time, &intensity)), _)| {
+                        // a stretched image turns with the velocity instead of the spin
+                        let (size, rotation) = if state.motion_blur {
+                            motion_blur(transform.size * TEXTURE_SCALE, velocity)
+                        } else {
+                            (transform.size * TEXTURE_SCALE, transform.rotation)
+                        };
+                        let pos = transform.position - size * 0.5;
+                        let color = match state.color_mode {
+                            ColorMode::Speed => {
+                                let speed_color = speed_to_color(velocity.length());
+                                if collision_time > 0.0 {
+                                    // `collision_time` is the time left, so the flash starts at the
+                                    // collision color and fades to the speed color as it runs out
+                                    let left = collision_time / COLLISION_COLOR_TIME;
+                                    interpolate_color(
+                                        speed_color,
+                                        ENTITY_COLLISION_COLOR,
+                                        1.0,
+                                        state.fade_curve.ease(left) * intensity,
+                                    )
+                                } else {
+                                    speed_color
+                                }
+                            }
+                            ColorMode::CollisionCount => collision_count_to_color(count),
+                        };
+                        draw.image(texture)
+                            .position(pos.x, pos.y)
+                            .size(size.x, size.y)
+                            .rotate_from((transform.position.x, transform.position.y), rotation)
+                            .color(color);
+                    },
+                );
+        });
+
+    state.particles.iter().for_each(|p| {
+        let pos = p.position - PARTICLE_SIZE * 0.5;
+        draw.rect((pos.x, pos.y), (PARTICLE_SIZE, PARTICLE_SIZE))
+            .color(ENTITY_COLLISION_COLOR)
+            .alpha(p.time_left / PARTICLE_LIFETIME);
+    });
+
+    state.world.obstacles.iter().for_each(|rect| {
+        draw.rect((rect.x, rect.y), (rect.width, rect.height))
+            .stroke(2.0)
+            .color(OBSTACLE_COLOR);
+    });
+
+    if state.world.margin > 0.0 && state.world.boundary != BoundaryMode::Wrap {
+        let bounds = state.world.bounds();
+        draw.rect((bounds.x, bounds.y), (bounds.width, bounds.height))
+            .stroke(1.0)
+            .color(BOUNDS_COLOR);
+    }
+
+    if state.debug_draw {
+        entities
+            .positions
+            .iter()
+            .zip(entities.radii.iter())
+            .zip(entities.is_colliding.iter())
+            .for_each(|((&position, &radius), &is_colliding)| {
+                let (min, max) = circle_aabb(position, radius);
+                let size = max - min;
+                let color = if is_colliding {
+                    DEBUG_AABB_COLLIDING_COLOR
+                } else {
+                    DEBUG_AABB_COLOR
+                };
+                draw.rect((min.x, min.y), (size.x, size.y))
+                    .stroke(1.0)
+                    .color(color);
+            });
+
+        state
+            .world
+            .contact_points
+            .iter()
+            .flatten()
+            .for_each(|contact| {
+                let length =
+                    (contact.penetration * DEBUG_CONTACT_SCALE).max(DEBUG_CONTACT_MIN_LENGTH);
+                let end = contact.point + contact.normal * length;
+                draw.line((contact.point.x, contact.point.y), (end.x, end.y))
+                    .width(1.0)
+                    .color(DEBUG_CONTACT_COLOR);
+            });
+
+        // the circle and ring textures look the same at any angle, a radius line shows the spin
+        sprites.transforms.iter().for_each(|t| {
+            let edge = t.position + Vec2::from_angle(t.rotation) * t.size.x * 0.5;
+            draw.line((t.position.x, t.position.y), (edge.x, edge.y))
+                .width(1.0)
+                .color(DEBUG_AABB_COLOR);
+        });
+    }
+
+    if state.density_draw {
+        let density = &state.world.density;
+        let max_count = density.counts.iter().copied().max().unwrap_or(0).max(1);
+        density
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .for_each(|(i, &count)| {
+                let x = (i % density.cols) as f32 * DENSITY_CELL_SIZE;
+                let y = (i / density.cols) as f32 * DENSITY_CELL_SIZE;
+                draw.rect((x, y), (DENSITY_CELL_SIZE, DENSITY_CELL_SIZE))
+                    .color(DENSITY_COLOR)
+                    .alpha(DENSITY_MAX_ALPHA * count as f32 / max_count as f32);
+            });
+    }
+
+    state.selected.iter().for_each(|&id| {
+        let transform = &sprites.transforms[id];
+        let size = transform.size * TEXTURE_SCALE;
+        let pos = transform.position - size * 0.5;
+        draw.image(&state.textures[sprites.texture_ids[id]])
             .position(pos.x, pos.y)
-            .size(e.transform.size.x, e.transform.size.y)
-            .color(color);
+            .size(size.x, size.y)
+            .rotate_from(
+                (transform.position.x, transform.position.y),
+                transform.rotation,
+            )
+            .color(SELECTED_COLOR);
     });
 
+    if let Some(rect) = state.selection {
+        draw.rect((rect.x, rect.y), (rect.width, rect.height))
+            .stroke(1.0)
+            .color(SELECTION_BOX_COLOR);
+    }
+
+    draw.transform().pop();
+
+    // -- hud
+    let mut hud = vec![
+        format!("FPS: {:.0}", app.timer.fps()),
+        format!("VSync: {}", if state.vsync { "on" } else { "off" }),
+        format!("Threads: {}", thread_count(state.thread_pool.as_ref())),
+        format!("Step: {:.3}ms", state.last_step_ms),
+        format!(
+            "Entities: {} ({} asleep)",
+            entities.len(),
+            entities.bodies.iter().filter(|b| b.asleep).count()
+        ),
+        format!("Seed: {}", state.seed),
+        format!("Time scale: {}x", state.time_scale),
+        format!("Spawn radius: {}", state.spawn_radius),
+        format!("Boundary: {:?}", state.world.boundary),
+        format!("Colors: {:?}", state.color_mode),
+        format!("Flash fade: {:?}", state.fade_curve),
+        format!(
+            "Resolver: {:?}, {} iterations",
+            state.world.resolver, state.world.resolve_iterations
+        ),
+        format!("Stuck: {}", entities.stuck_count()),
+        format!("Friction: {}", state.world.friction),
+        format!("Sleep: {}", if state.world.sleep { "on" } else { "off" }),
+        format!(
+            "Attractor: {}",
+            if state.world.attract_strength != 0.0 {
+                "on"
+            } else {
+                "off"
+            }
+        ),
+        format!(
+            "Avoid crowds: {}",
+            if state.world.avoid_density {
+                "on"
+            } else {
+                "off"
+            }
+        ),
+        format!("Energy: {:.0}", state.kinetic_energy),
+        format!("Momentum: {:.0}", state.momentum),
+        format!(
+            "Broadphase: {:?}{}",
+            state.world.broadphase.kind,
+            if state.morton_sort { " (morton)" } else { "" }
+        ),
+        format!(
+            "Pairs: {} collisions / {} candidates",
+            state.world.stats.collision_pairs, state.world.stats.candidate_pairs
+        ),
+    ];
+    if let Some(id) = state.hovered {
+        hud.push(format!(
+            "Entity {}: radius {}, speed {:.0}",
+            id,
+            entities.radii[id],
+            entities.velocities[id].length()
+        ));
+        if entities.follow_mouse[id] {
+            hud.push(format!(
+                "Following, gain {:.1}",
+                entities.follow_strengths[id]
+            ));
+        }
+    }
+    if state.selection.is_some() {
+        hud.push(format!("Selected: {}", state.selected.len()));
+    }
+    if state.spawns_skipped_time > 0.0 {
+        hud.push(format!(
+            "Too crowded, skipped {} spawns",
+            state.spawns_skipped
+        ));
+    }
+    if state.pause {
+        hud.push("PAUSED (. to step)".to_string());
+    }
+    draw.text(&state.font, &hud.join("\n"))
+        .position(10.0, 10.0)
+        .size(16.0)
+        .color(Color::WHITE);
+
+    // -- fps graph, scaled to the highest fps it shows
+    let (_, height) = gfx.size();
+    let origin = vec2(10.0, height as f32 - FPS_GRAPH_SIZE.y - 10.0);
+    draw.rect((origin.x, origin.y), (FPS_GRAPH_SIZE.x, FPS_GRAPH_SIZE.y))
+        .color(FPS_GRAPH_BACKGROUND);
+    let max_fps = state.fps_history.iter().copied().fold(1.0, f32::max);
+    let step = FPS_GRAPH_SIZE.x / (FPS_GRAPH_SAMPLES - 1) as f32;
+    let points: Vec<Vec2> = state
+        .fps_history
+        .iter()
+        .enumerate()
+        .map(|(i, fps)| origin + vec2(i as f32 * step, FPS_GRAPH_SIZE.y * (1.0 - fps / max_fps)))
+        .collect();
+    points.windows(2).for_each(|segment| {
+        draw.line((segment[0].x, segment[0].y), (segment[1].x, segment[1].y))
+            .width(1.0)
+            .color(FPS_GRAPH_COLOR);
+    });
+    draw.text(&state.font, &format!("{:.0}", max_fps))
+        .position(origin.x + 4.0, origin.y + 4.0)
+        .size(12.0)
+        .color(FPS_GRAPH_COLOR);
+
     gfx.render(&draw);
 }
 
-fn init_entities() -> Vec<Entity> {
-    let mut rng = Random::default();
-    (0..INITIAL_ENTITIES)
-        .map(|_| {
-            let min_pos = vec2(50.0, 50.0);
-            let max_pos = vec2(GAME_WIDTH - min_pos.x * 2.0, GAME_HEIGHT - min_pos.y * 2.0);
-            let position = vec2(
-                min_pos.x + rng.gen::<f32>() * max_pos.x,
-                min_pos.y + rng.gen::<f32>() * max_pos.y,
-            );
-            let min_vel = INITIAL_VELOCITY * -0.5;
-            let max_vel = INITIAL_VELOCITY;
-            let velocity = vec2(
-                min_vel + rng.gen::<f32>() * max_vel,
-                min_vel + rng.gen::<f32>() * max_vel,
-            );
-            Entity {
-                body: Body {
-                    position,
-                    velocity,
-                    radius: ENTITY_RADIUS,
-                },
-                transform: Transform {
-                    position,
-                    size: Vec2::splat(ENTITY_RADIUS * 2.0),
-                },
-                is_colliding: false,
-                collision_time: 0.0,
-            }
-        })
-        .collect()
+fn spawn_burst(state: &mut State) {
+    state.world.update_density();
+    (0..BURST_ENTITIES).for_each(|_| {
+        let entity = random_entity(&mut state.rng, state.spawn_radius, state.world.size);
+        spawn_if_room(state, entity);
+    });
 }
 
-fn is_colliding(p1: Vec2, r1: f32, p2: Vec2, r2: f32) -> bool {
-    let sum_radius = r1 + r2;
-    let square_radius = sum_radius * sum_radius;
-    let square_distance = p1.distance_squared(p2);
-    square_distance <= square_radius
+/// Adds the entity unless its cell of `World::density` already holds `SPAWN_MAX_DENSITY`
+/// bodies, then it's counted in `State::spawns_skipped`. The grid has to be up to date.
+/// Once there are `State::max_entities` the oldest entity is recycled as the new one, or
+/// nothing is spawned when all of them are followers.
+fn spawn_if_room(state: &mut State, entity: Entity) {
+    let texture_id = cycled_texture(state.world.entities.len());
+    let position = entity.position;
+    if state.world.density.count_at(position) >= SPAWN_MAX_DENSITY {
+        state.spawns_skipped += 1;
+        state.spawns_skipped_time = SPAWN_SKIPPED_MESSAGE_TIME;
+        return;
+    }
+    state.world.density.add(position);
+    if state.world.entities.len() < state.max_entities {
+        state.world.spawn(entity);
+        state.sprites.push(texture_id);
+    } else if let Some(id) = state.world.entities.oldest_recyclable(state.grabbed) {
+        state.world.entities.replace(id, entity);
+        state.sprites.replace(id, texture_id);
+    }
 }
 
-fn interpolate_color(c1: Color, c2: Color, total_time: f32, elapsed: f32) -> Color {
-    let c1: Vec3 = c1.rgb().into();
-    let c2: Vec3 = c2.rgb().into();
-    let delta = c2 - c1;
-    let fc = c1 + delta * (elapsed / total_time);
-    Color::from_rgb(fc.x, fc.y, fc.z)
+/// The big circle that follows the mouse, drawn with the first texture
+fn big_circle(position: Vec2) -> Entity {
+    Entity {
+        follow_mouse: true,
+        ..create_entity(position, Vec2::ZERO, BIG_CIRCLE_RADIUS)
+    }
 }
 
-// systems
-fn sys_clean_collisions(entities: &mut [Entity], delta: f32) {
-    entities.iter_mut().for_each(|e| {
-        e.is_colliding = false;
-        if e.collision_time > 0.0 {
-            e.collision_time -= delta;
-        }
-    });
+/// Grows the big circle, the biggest mouse follower, up to `BIG_CIRCLE_MAX_RADIUS`. The
+/// bodies it now overlaps are pushed out by the resolver like any other contact. It waits
+/// while one of them is still overlapping as deep as a stuck body, a packed crowd can't
+/// make room as fast as it grows and would end up inside it.
+fn grow_big_circle(world: &mut World, growth: f32) {
+    let Entities {
+        radii,
+        follow_mouse,
+        ..
+    } = &world.entities;
+    let Some(id) = (0..radii.len())
+        .filter(|&id| follow_mouse[id])
+        .max_by(|&id1, &id2| radii[id1].total_cmp(&radii[id2]))
+    else {
+        return;
+    };
+
+    if !world.is_crowded(id) {
+        let radius = (radii[id] + growth).min(BIG_CIRCLE_MAX_RADIUS);
+        world.resize(id, radius);
+        world.entities.bodies[id].wake();
+    }
 }
 
-fn sys_check_collision(entities: &mut [Entity]) -> Vec<Collision> {
-    // TODO do not nest loops, use spatial hashing
-    let mut colliding = vec![]; // todo maybe use a hashset?
-    entities.iter().enumerate().for_each(|(id1, e1)| {
-        entities.iter().enumerate().for_each(|(id2, e2)| {
-            if id1 == id2 {
-                return;
-            }
+fn spawn_small_at_cursor(state: &mut State, position: Vec2) {
+    state.world.update_density();
+    let velocity = random_velocity(&mut state.rng);
+    let entity = create_entity(position, velocity, state.spawn_radius);
+    spawn_if_room(state, entity);
+}
 
-            if is_colliding(
-                e1.body.position,
-                e1.body.radius,
-                e2.body.position,
-                e2.body.radius,
-            ) {
-                let collision = Collision([id1, id2]);
-                if !colliding.contains(&collision) {
-                    colliding.push(collision);
-                }
-            }
-        });
-    });
+/// Spawns entities from `from` towards `to`, one every diameter so they don't overlap,
+/// moving along the stroke. Returns where the stroke continues from, the last spawn or
+/// `to` when `MAX_STROKE_SPAWNS` cut it short.
+fn spawn_along_stroke(state: &mut State, from: Vec2, to: Vec2) -> Vec2 {
+    let spacing = state.spawn_radius * 2.0;
+    let delta = to - from;
+    let count = (delta.length() / spacing) as usize;
+    if count == 0 {
+        return from;
+    }
 
-    colliding.iter().for_each(|Collision([id1, id2])| {
-        entities[*id1].is_colliding = true;
-        entities[*id1].collision_time = COLLISION_COLOR_TIME;
-        entities[*id2].is_colliding = true;
-        entities[*id2].collision_time = COLLISION_COLOR_TIME;
+    let direction = delta.normalize();
+    state.world.update_density();
+    (1..=count.min(MAX_STROKE_SPAWNS)).for_each(|i| {
+        let position = from + direction * spacing * i as f32;
+        let entity = create_entity(position, direction * STROKE_SPEED, state.spawn_radius);
+        spawn_if_room(state, entity);
     });
+    if count > MAX_STROKE_SPAWNS {
+        to
+    } else {
+        from + direction * spacing * count as f32
+    }
+}
+
+/// Scene of the startup, the same one for the same seed and size
+fn initial_entities(rng: &mut Random, size: Vec2) -> (Entities, Sprites) {
+    // the lattice is left alone for the experiments, the follower would start inside it
+    if let Some((cols, rows)) = lattice_arg() {
+        let entities = init_entities_grid(cols, rows, LATTICE_SPACING, size, &default_obstacles());
+        let sprites = Sprites::from_textures((0..entities.len()).map(cycled_texture));
+        return (entities, sprites);
+    }
 
-    colliding
+    let entities = init_entities(
+        rng,
+        INITIAL_ENTITIES,
+        INITIAL_MIN_RADIUS,
+        INITIAL_MAX_RADIUS,
+        size,
+    );
+    with_big_circle(entities, size)
 }
 
-fn sys_resolve_collisions(entities: &mut [Entity], collisions: Vec<Collision>) {
-    collisions.into_iter().for_each(|Collision([id1, id2])| {
-        let b1 = &entities[id1].body;
-        let b2 = &entities[id2].body;
+/// Adds the big circle in the middle of the world to a scene of random entities
+fn with_big_circle(mut entities: Entities, size: Vec2) -> (Entities, Sprites) {
+    let mut sprites = Sprites::from_textures((0..entities.len()).map(cycled_texture));
+    entities.push(big_circle(size * 0.5));
+    sprites.push(0);
+    (entities, sprites)
+}
 
-        let sum_radius = b1.radius + b2.radius;
-        let pos_delta = b1.position - b2.position;
-        let magnitude = pos_delta.length();
-        let min_translation_distance = pos_delta * (sum_radius - magnitude) / magnitude;
+/// Brings back the scene of the startup, the settings toggled with keys are kept
+fn reset_scene(state: &mut State) {
+    let size = state.world.size;
+    state.rng = Random::new(state.seed);
+    (state.world.entities, state.sprites) = initial_entities(&mut state.rng, size);
+    state.world.broadphase = Broadphase::new(
+        state.world.broadphase.kind,
+        grid_cell_size(INITIAL_MAX_RADIUS),
+        size,
+    );
+    if let Some(events) = state.world.collision_events.as_mut() {
+        events.clear();
+    }
+    state.pause = false;
+    state.camera = Camera::default();
+    state.accumulator = 0.0;
+    state.particles.clear();
+    state.grabbed = None;
+    state.hovered = None;
+    clear_selection(state);
+}
 
-        let vel_delta = b1.velocity - b2.velocity;
-        let normalized_mtd = min_translation_distance.normalize();
-        let relative_vel = vel_delta.dot(normalized_mtd);
+/// Replaces the scene with a new random one using the current spawn radius
+fn regenerate_scene(state: &mut State) {
+    let size = state.world.size;
+    let entities = init_entities(
+        &mut state.rng,
+        INITIAL_ENTITIES,
+        state.spawn_radius,
+        state.spawn_radius,
+        size,
+    );
+    (state.world.entities, state.sprites) = with_big_circle(entities, size);
+    state.grabbed = None;
+    clear_selection(state);
+    state.world.broadphase = Broadphase::new(
+        state.world.broadphase.kind,
+        grid_cell_size(state.spawn_radius),
+        size,
+    );
+}
 
-        if relative_vel > 0.0 {
-            return;
-        }
+/// Writes every entity to `path` as json
+fn save_state(state: &State, path: &str) -> Result<(), String> {
+    let saved: Vec<SavedEntity> = state
+        .world
+        .entities
+        .to_vec()
+        .into_iter()
+        .zip(&state.sprites.texture_ids)
+        .map(|(entity, &texture_id)| SavedEntity { entity, texture_id })
+        .collect();
+    let json = serde_json::to_string(&saved).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| e.to_string())
+}
+
+/// Reads the entities written by `save_state`
+fn load_state(path: &str) -> Result<Vec<SavedEntity>, String> {
+    let json = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    parse_state(&json)
+}
+
+/// Entities of a save, older saves are moved to the current layout first
+fn parse_state(json: &str) -> Result<Vec<SavedEntity>, String> {
+    let mut saved: Vec<serde_json::Value> =
+        serde_json::from_str(json).map_err(|e| e.to_string())?;
+    saved.iter_mut().for_each(migrate_saved_entity);
+    saved
+        .into_iter()
+        .map(|entity| serde_json::from_value(entity).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Saves written before the position, velocity and radius moved out of `Body` nest them
+/// inside it, this moves them up to the entity where `Entity` reads them now
+fn migrate_saved_entity(entity: &mut serde_json::Value) {
+    let Some(entity) = entity.as_object_mut() else {
+        return;
+    };
+    if entity.contains_key("position") {
+        return;
+    }
+    let Some(body) = entity.get_mut("body").and_then(|b| b.as_object_mut()) else {
+        return;
+    };
+    let moved: Vec<_> = ["position", "velocity", "radius"]
+        .into_iter()
+        .filter_map(|key| body.remove(key).map(|value| (key.to_string(), value)))
+        .collect();
+    entity.extend(moved);
+}
+
+fn despawn(state: &mut State, idx: usize) {
+    let last = state.world.entities.len() - 1;
+    state.world.despawn(idx);
+    state.sprites.swap_remove(idx);
+
+    // keep the grab and the selection pointing to the same entities after the swap
+    state.grabbed = state.grabbed.and_then(|id| id_after_despawn(id, idx, last));
+    state.selected = state
+        .selected
+        .iter()
+        .filter_map(|&id| id_after_despawn(id, idx, last))
+        .collect();
+}
+
+/// Id of the entity `id` after `World::despawn(removed)` moved the `last` one into the
+/// place of the removed one, none for the removed one itself
+fn id_after_despawn(id: usize, removed: usize, last: usize) -> Option<usize> {
+    if id == removed {
+        None
+    } else if id == last {
+        Some(removed)
+    } else {
+        Some(id)
+    }
+}
+
+/// Updates the ids kept outside the world after the entities were reordered
+fn remap_ids(state: &mut State, order: &[usize]) {
+    state.sprites.reorder(order);
+    let mut new_ids = vec![0; order.len()];
+    order
+        .iter()
+        .enumerate()
+        .for_each(|(new_id, &old_id)| new_ids[old_id] = new_id);
+    state.grabbed = state.grabbed.map(|id| new_ids[id]);
+    state.selected.iter_mut().for_each(|id| *id = new_ids[*id]);
+    state.selected.sort_unstable();
+}
+
+fn clear_selection(state: &mut State) {
+    state.selection_start = None;
+    state.selection = None;
+    state.selected.clear();
+}
 
-        let normalized_rel_vel = normalized_mtd * relative_vel;
+/// Average velocity of the cursor over `history`, the distance from the oldest to the
+/// newest position over the time between them. Zero with less than two samples.
+fn cursor_velocity(history: &VecDeque<(Vec2, f32)>) -> Vec2 {
+    let (Some((first, _)), Some((last, _))) = (history.front(), history.back()) else {
+        return Vec2::ZERO;
+    };
+    // the delta of the oldest sample is the time before it, outside the window
+    let time: f32 = history.iter().skip(1).map(|(_, delta)| delta).sum();
+    if time > 0.0 {
+        (*last - *first) / time
+    } else {
+        Vec2::ZERO
+    }
+}
 
-        entities[id1].body.velocity -= normalized_rel_vel;
-        entities[id1].body.position += min_translation_distance;
-        entities[id2].body.velocity += normalized_rel_vel;
-        entities[id2].body.position -= min_translation_distance;
+/// Turns the selected entities into mouse followers with a random steering gain each, or
+/// back into regular entities if all of them already follow the mouse
+fn toggle_selected_followers(state: &mut State) {
+    let entities = &mut state.world.entities;
+    let follow = !state.selected.iter().all(|&id| entities.follow_mouse[id]);
+    state.selected.iter().for_each(|&id| {
+        entities.follow_mouse[id] = follow;
+        entities.follow_strengths[id] = if follow {
+            FOLLOW_MIN_STEERING_GAIN
+                + state.rng.gen::<f32>() * (FOLLOW_STEERING_GAIN - FOLLOW_MIN_STEERING_GAIN)
+        } else {
+            FOLLOW_STEERING_GAIN
+        };
     });
 }
 
-fn sys_bounce_rect(entities: &mut [Entity]) {
-    entities.iter_mut().for_each(|e| {
-        let left = e.body.position.x - e.body.radius <= 0.0;
-        if left {
-            e.body.velocity.x *= -1.0;
-            e.body.position.x = e.body.radius;
-        }
-        let right = e.body.position.x + e.body.radius >= GAME_WIDTH;
-        if right {
-            e.body.velocity.x *= -1.0;
-            e.body.position.x = GAME_WIDTH - e.body.radius;
-        }
-        let top = e.body.position.y - e.body.radius < 0.0;
-        if top {
-            e.body.velocity.y *= -1.0;
-            e.body.position.y = e.body.radius;
-        }
-        let bottom = e.body.position.y + e.body.radius >= GAME_HEIGHT;
-        if bottom {
-            e.body.velocity.y *= -1.0;
-            e.body.position.y = GAME_HEIGHT - e.body.radius;
+/// Despawns everything but the mouse followers
+fn despawn_all(state: &mut State) {
+    // reverse order so swap_remove never moves an entity we still have to visit
+    (0..state.world.entities.len()).rev().for_each(|idx| {
+        if !state.world.entities.follow_mouse[idx] {
+            despawn(state, idx);
         }
     });
 }
 
-fn sys_apply_velocity_to_body(entities: &mut [Entity], delta: f32) {
-    entities.iter_mut().for_each(|e| {
-        e.body.position += e.body.velocity * delta;
+/// Blends from `c1` when `value` is 0.0 to `c2` when it reaches `max`, values outside of
+/// that range get the color of the nearest end
+fn interpolate_color(c1: Color, c2: Color, max: f32, value: f32) -> Color {
+    let c1: Vec3 = c1.rgb().into();
+    let c2: Vec3 = c2.rgb().into();
+    let t = (value / max).clamp(0.0, 1.0);
+    let fc = c1.lerp(c2, t);
+    Color::from_rgb(fc.x, fc.y, fc.z)
+}
+
+/// Size and rotation that stretch an image of `size` along `velocity`, longer the faster
+/// it goes up to `MAX_MOTION_BLUR_STRETCH`. Only drawn, the body keeps its radius.
+fn motion_blur(size: Vec2, velocity: Vec2) -> (Vec2, f32) {
+    let stretch = (1.0 + velocity.length() * MOTION_BLUR_SCALE).min(MAX_MOTION_BLUR_STRETCH);
+    (vec2(size.x * stretch, size.y), velocity.y.atan2(velocity.x))
+}
+
+/// Lines between the cells of the grid broadphase, with the cells tinted by how many
+/// bodies they hold. The cells are only filled while the grid or the two tier broadphase
+/// is in use.
+fn draw_broadphase_grid(draw: &mut Draw, broadphase: &Broadphase) {
+    let grid = &broadphase.grid;
+    let size = vec2(grid.cols as f32, grid.rows as f32) * grid.cell_size;
+
+    if matches!(
+        broadphase.kind,
+        BroadphaseKind::Grid | BroadphaseKind::TwoTier
+    ) {
+        let max_count = grid.cells.iter().map(Vec::len).max().unwrap_or(0).max(1);
+        grid.cells
+            .iter()
+            .enumerate()
+            .filter(|(_, cell)| !cell.is_empty())
+            .for_each(|(i, cell)| {
+                let x = (i % grid.cols) as f32 * grid.cell_size;
+                let y = (i / grid.cols) as f32 * grid.cell_size;
+                draw.rect((x, y), (grid.cell_size, grid.cell_size))
+                    .color(GRID_CELL_COLOR)
+                    .alpha(GRID_CELL_MAX_ALPHA * cell.len() as f32 / max_count as f32);
+            });
+    }
+
+    (0..=grid.cols).for_each(|col| {
+        let x = col as f32 * grid.cell_size;
+        draw.line((x, 0.0), (x, size.y))
+            .width(1.0)
+            .color(GRID_LINE_COLOR)
+            .alpha(GRID_LINE_ALPHA);
     });
+    (0..=grid.rows).for_each(|row| {
+        let y = row as f32 * grid.cell_size;
+        draw.line((0.0, y), (size.x, y))
+            .width(1.0)
+            .color(GRID_LINE_COLOR)
+            .alpha(GRID_LINE_ALPHA);
+    });
+}
+
+/// Gradient from `SLOW_ENTITY_COLOR` at rest to `FAST_ENTITY_COLOR` at `MAX_COLOR_SPEED`
+fn speed_to_color(speed: f32) -> Color {
+    interpolate_color(
+        SLOW_ENTITY_COLOR,
+        FAST_ENTITY_COLOR,
+        MAX_COLOR_SPEED,
+        speed.min(MAX_COLOR_SPEED),
+    )
 }
 
-fn sys_body_to_transform(entites: &mut [Entity]) {
-    entites.iter_mut().for_each(|e| {
-        e.transform.position = e.body.position;
+/// Gradient from `NO_COLLISIONS_COLOR` to `MANY_COLLISIONS_COLOR` at `MAX_COLOR_COLLISIONS`
+fn collision_count_to_color(count: u32) -> Color {
+    interpolate_color(
+        NO_COLLISIONS_COLOR,
+        MANY_COLLISIONS_COLOR,
+        MAX_COLOR_COLLISIONS as f32,
+        count.min(MAX_COLOR_COLLISIONS) as f32,
+    )
+}
+
+fn sys_update_particles(particles: &mut Vec<Particle>, delta: f32) {
+    particles.retain_mut(|p| {
+        p.position += p.velocity * delta;
+        p.time_left -= delta;
+        p.time_left > 0.0
     });
 }
+
+fn sys_fade_flashes(collision_times: &mut [f32], collision_intensities: &mut [f32], delta: f32) {
+    collision_times
+        .iter_mut()
+        .zip(collision_intensities)
+        .for_each(|(time, intensity)| {
+            if *time > 0.0 {
+                *time -= delta;
+            }
+            if *time <= 0.0 {
+                *intensity = 0.0;
+            }
+        });
+}
+
+fn sys_record_trails(transforms: &[Transform], trails: &mut [VecDeque<Vec2>]) {
+    trails.iter_mut().zip(transforms).for_each(|(trail, t)| {
+        if trail.len() == TRAIL_LENGTH {
+            trail.pop_front();
+        }
+        trail.push_back(t.position);
+    });
+}
+
+/// `alpha` is the fraction of a step left in the accumulator, 0.0 is the previous step
+fn sys_body_to_transform(
+    positions: &[Vec2],
+    radii: &[f32],
+    bodies: &[Body],
+    transforms: &mut [Transform],
+    alpha: f32,
+) {
+    transforms
+        .iter_mut()
+        .zip(positions.iter().zip(radii).zip(bodies))
+        .for_each(|(t, ((&position, &radius), b))| {
+            t.position = b.last_position.lerp(position, alpha);
+            t.size = Vec2::splat(radius * 2.0);
+            t.rotation = b.rotation;
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_color_goes_from_one_end_to_the_other() {
+        let (c1, c2) = (Color::BLUE, Color::RED);
+        assert_eq!(interpolate_color(c1, c2, 500.0, 0.0), c1);
+        assert_eq!(interpolate_color(c1, c2, 500.0, 500.0), c2);
+        assert_eq!(interpolate_color(c1, c2, 500.0, 2000.0), c2);
+        assert_eq!(
+            interpolate_color(c1, c2, 500.0, 250.0),
+            Color::from_rgb(0.5, 0.0, 0.5)
+        );
+    }
+
+    #[test]
+    fn a_huge_delta_runs_a_bounded_number_of_steps() {
+        // a 10 second stall is clamped to MAX_DELTA before it reaches the accumulator
+        let mut accumulator = 0.0;
+        let steps = fixed_steps(&mut accumulator, 10.0, 1.0);
+        let clamped = (MAX_DELTA / FIXED_DELTA) as usize;
+        assert!(steps.abs_diff(clamped) <= 1, "{steps} steps");
+        assert!(steps <= MAX_STEPS_PER_FRAME);
+        assert!((0.0..FIXED_DELTA).contains(&accumulator));
+
+        // a backlog longer than the steps allowed per frame is dropped
+        let mut accumulator = 1.0;
+        assert_eq!(
+            fixed_steps(&mut accumulator, 10.0, 1.0),
+            MAX_STEPS_PER_FRAME
+        );
+        assert_eq!(accumulator, 0.0);
+
+        // fast-forwarding allows more steps, still bounded
+        let mut accumulator = 1.0;
+        let scale = *TIME_SCALES.last().unwrap();
+        let steps = fixed_steps(&mut accumulator, 10.0, scale);
+        assert_eq!(steps, MAX_STEPS_PER_FRAME * scale as usize);
+        assert_eq!(accumulator, 0.0);
+    }
+
+    #[test]
+    fn saves_load_in_the_current_and_the_old_layout() {
+        let saved = [SavedEntity {
+            entity: big_circle(vec2(100.0, 200.0)),
+            texture_id: 2,
+        }];
+        let loaded = parse_state(&serde_json::to_string(&saved).unwrap()).unwrap();
+        assert_eq!(loaded[0].entity.position, vec2(100.0, 200.0));
+        assert_eq!(loaded[0].entity.radius, BIG_CIRCLE_RADIUS);
+        assert_eq!(loaded[0].texture_id, 2);
+
+        // written before the position, velocity and radius left the body
+        let old = r#"[{
+            "body": {
+                "position": [100.0, 200.0],
+                "last_position": [100.0, 200.0],
+                "velocity": [3.0, 4.0],
+                "force": [0.0, 0.0],
+                "radius": 12.0,
+                "mass": 452.0,
+                "layer": 1,
+                "mask": 4294967295
+            },
+            "transform": { "position": [100.0, 200.0], "size": [24.0, 24.0] },
+            "is_colliding": false,
+            "collision_time": 0.0,
+            "texture_id": 1
+        }]"#;
+        let loaded = parse_state(old).unwrap();
+        assert_eq!(loaded[0].entity.position, vec2(100.0, 200.0));
+        assert_eq!(loaded[0].entity.velocity, vec2(3.0, 4.0));
+        assert_eq!(loaded[0].entity.radius, 12.0);
+        assert_eq!(loaded[0].texture_id, 1);
+    }
+
+    #[test]
+    fn ids_follow_the_entity_moved_by_a_despawn() {
+        assert_eq!(id_after_despawn(2, 2, 5), None);
+        assert_eq!(id_after_despawn(5, 2, 5), Some(2));
+        assert_eq!(id_after_despawn(3, 2, 5), Some(3));
+        // removing the last one moves nothing
+        assert_eq!(id_after_despawn(5, 5, 5), None);
+        assert_eq!(id_after_despawn(0, 5, 5), Some(0));
+    }
+
+    #[test]
+    fn sprites_follow_the_entity_ids() {
+        let mut sprites = Sprites::from_textures([0, 1, 2, 1]);
+        sprites.swap_remove(1);
+        assert_eq!(sprites.texture_ids, vec![0, 1, 2]);
+        sprites.reorder(&[2, 0, 1]);
+        assert_eq!(sprites.texture_ids, vec![2, 0, 1]);
+        assert_eq!(sprites.trails.len(), 3);
+    }
+
+    #[test]
+    fn flashes_fade_out_with_their_intensity() {
+        let mut times = vec![0.5, 0.1];
+        let mut intensities = vec![1.0, 1.0];
+        sys_fade_flashes(&mut times, &mut intensities, 0.2);
+        assert_eq!(intensities, vec![1.0, 0.0]);
+    }
+}
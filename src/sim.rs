@@ -0,0 +1,2930 @@
+//! Bodies, collision detection and response, everything a step of the world does. Nothing
+//! here draws or reads input, the window and the headless mode both drive a `World`.
+
+use notan::math::{vec2, Rect, Vec2};
+use notan::random::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::f32::consts::{PI, TAU};
+use std::hash::{Hash, Hasher};
+
+/// Speed scale of the random velocities of new entities, see `VelocityDistribution`
+const INITIAL_SPEED: f32 = 125.0;
+const INITIAL_VELOCITY_DISTRIBUTION: VelocityDistribution = VelocityDistribution::Uniform;
+/// Default radius for spawned entities, can be changed at runtime
+pub const ENTITY_RADIUS: f32 = 16.0;
+/// Closest the lattice entities can be, a hair over touching
+const LATTICE_MIN_SPACING: f32 = ENTITY_RADIUS * 2.0 + CONTACT_SLOP;
+/// Side of the cells of `DensityGrid`
+pub const DENSITY_CELL_SIZE: f32 = 50.0;
+/// Smallest side of the regions the islands resolver solves in parallel, they grow to fit
+/// the largest body
+const ISLAND_REGION_SIZE: f32 = 64.0;
+/// Thickness of the walls of `BoundaryMode::Walls`. A body is pushed back inside as long
+/// as its center ends less than half of it past the edge
+const ARENA_WALL_THICKNESS: f32 = 1000.0;
+/// Inset of the bounce and wall edges from the edges of the world, keeps the bodies out
+/// from under the HUD. 0.0 uses the whole world.
+const WORLD_MARGIN: f32 = 0.0;
+/// Restitution of new bodies, 1.0 is perfectly elastic
+const RESTITUTION: f32 = 1.0;
+/// Closer than this the attraction stops growing, so it can't blow up at the cursor
+const ATTRACT_MIN_DISTANCE: f32 = 20.0;
+/// Sideways acceleration the followers use to go around a crowded density cell
+const AVOID_ACCELERATION: f32 = 3000.0;
+/// Bodies in the cell ahead of a follower that make it swerve with the full
+/// `AVOID_ACCELERATION`
+const AVOID_FULL_COUNT: u32 = 4;
+/// Bodies slower than this for `SLEEP_TIME` seconds fall asleep, it has to be above the
+/// speed gravity adds in one step or resting bodies would never fall asleep
+const SLEEP_SPEED: f32 = 15.0;
+const SLEEP_TIME: f32 = 0.5;
+/// Below this distance two centers are considered coincident
+const MIN_CONTACT_DISTANCE: f32 = 0.0001;
+/// Penetration left uncorrected so resting contacts don't jitter
+const CONTACT_SLOP: f32 = 0.05;
+/// Largest position correction per pair and step, as a fraction of the smaller radius. The
+/// Jacobi resolver caps the sum of the corrections of a body by it too, with its own radius
+const MAX_CORRECTION_FRACTION: f32 = 0.2;
+/// Overlap left after the resolution, as a fraction of the smaller radius, that counts
+/// towards being stuck
+const STUCK_OVERLAP_FRACTION: f32 = 0.25;
+/// Steps in a row deeply overlapping before a body is reported as stuck, one second
+const STUCK_STEPS: u32 = 120;
+/// Layer and mask bits for bodies that collide with everything
+pub const ALL_LAYERS: u32 = u32::MAX;
+/// Most sub-steps a fast body is split into, bodies moving more than this many radii
+/// in one step can still tunnel
+const MAX_SUBSTEPS: usize = 8;
+/// Deepest level the quadtree splits to, deeper boxes stay in the last node
+const QUADTREE_MAX_DEPTH: usize = 8;
+/// Bodies a quadtree node holds before splitting into four
+const QUADTREE_NODE_CAPACITY: usize = 8;
+/// Cell size of the coarse grid of `BroadphaseKind::TwoTier` as a multiple of the fine one
+const COARSE_CELL_SCALE: f32 = 4.0;
+/// Top speed the mouse followers steer towards
+const FOLLOW_MAX_SPEED: f32 = 600.0;
+/// Desired speed per unit of distance to the cursor, so the followers slow down on arrival
+const FOLLOW_ARRIVE_GAIN: f32 = 4.0;
+/// How fast a follower corrects its velocity towards the desired one, per second.
+/// Default of every entity, so the big circle uses it
+pub const FOLLOW_STEERING_GAIN: f32 = 8.0;
+/// Smaller entities can still be picked this far from their center
+const MIN_PICK_RADIUS: f32 = 8.0;
+
+/// Unordered pair of colliding entities, always stored as `[lower_id, higher_id]`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Collision([usize; 2]);
+
+/// Components of a body every step doesn't need. The position, velocity, force and radius
+/// have their own arrays in `Entities` so the integrator and the collision loops only go
+/// through those.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Body {
+    /// Position at the end of the previous step, used to interpolate the rendering
+    pub last_position: Vec2,
+    mass: f32,
+    /// Bits of the layers this body belongs to
+    pub layer: u32,
+    /// Bits of the layers this body collides with, a body with no bits set is a ghost
+    /// that goes through everything
+    pub mask: u32,
+    /// How much of the approach speed is kept when bouncing, the lowest wins between bodies
+    #[serde(default = "default_restitution")]
+    restitution: f32,
+    /// Seconds the body has been slower than `SLEEP_SPEED`
+    #[serde(default)]
+    sleep_time: f32,
+    /// Asleep bodies don't move until a force or a collision wakes them
+    #[serde(default)]
+    pub asleep: bool,
+    /// Radians per second, only friction makes the bodies spin
+    #[serde(default)]
+    angular_velocity: f32,
+    /// Angle in radians, turned by `angular_velocity`
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+impl Body {
+    /// Makes the body move again, simulated until it rests long enough to sleep
+    pub fn wake(&mut self) {
+        self.asleep = false;
+        self.sleep_time = 0.0;
+    }
+}
+
+fn default_restitution() -> f32 {
+    RESTITUTION
+}
+
+/// Bundle of components used to spawn an entity into `Entities`
+#[derive(Serialize, Deserialize)]
+pub struct Entity {
+    /// Center of the circle
+    pub position: Vec2,
+    /// Pixels per second
+    pub velocity: Vec2,
+    /// Radius of the circle, the mass grows with its area
+    pub radius: f32,
+    /// The rest of the body
+    pub body: Body,
+    /// Overlaps another body in the last step
+    pub is_colliding: bool,
+    /// Bodies this one overlaps in the last step
+    #[serde(default)]
+    pub collision_count: u32,
+    /// Steps in a row it was left deep inside another body, see `sys_track_stuck`
+    #[serde(default)]
+    pub stuck_steps: u32,
+    /// Steers towards the cursor instead of falling with gravity
+    #[serde(default)]
+    pub follow_mouse: bool,
+    /// Steering gain used while following the mouse
+    #[serde(default = "default_follow_strength")]
+    pub follow_strength: f32,
+}
+
+fn default_follow_strength() -> f32 {
+    FOLLOW_STEERING_GAIN
+}
+
+/// Entities stored as parallel component arrays, the index is the entity id
+#[derive(Default)]
+pub struct Entities {
+    /// See `Entity::position`
+    pub positions: Vec<Vec2>,
+    /// See `Entity::velocity`
+    pub velocities: Vec<Vec2>,
+    /// Forces accumulated for the next integration, cleared by it
+    forces: Vec<Vec2>,
+    /// See `Entity::radius`
+    pub radii: Vec<f32>,
+    /// See `Entity::body`
+    pub bodies: Vec<Body>,
+    /// See `Entity::is_colliding`
+    pub is_colliding: Vec<bool>,
+    /// See `Entity::collision_count`
+    pub collision_counts: Vec<u32>,
+    stuck_steps: Vec<u32>,
+    /// See `Entity::follow_mouse`
+    pub follow_mouse: Vec<bool>,
+    /// See `Entity::follow_strength`
+    pub follow_strengths: Vec<f32>,
+    /// When each entity was pushed or recycled, lower is older
+    spawn_orders: Vec<u64>,
+    /// Spawn order of the next entity
+    next_spawn_order: u64,
+}
+
+impl Entities {
+    /// Number of entities, the ids go from 0 to it
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// True if there are no entities
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Entities stuck for at least `STUCK_STEPS`
+    pub fn stuck_count(&self) -> usize {
+        self.stuck_steps
+            .iter()
+            .filter(|&&steps| steps >= STUCK_STEPS)
+            .count()
+    }
+
+    /// Adds the entity with the next id
+    pub fn push(&mut self, entity: Entity) {
+        self.positions.push(entity.position);
+        self.velocities.push(entity.velocity);
+        self.forces.push(Vec2::ZERO);
+        self.radii.push(entity.radius);
+        self.bodies.push(entity.body);
+        self.is_colliding.push(entity.is_colliding);
+        self.collision_counts.push(entity.collision_count);
+        self.stuck_steps.push(entity.stuck_steps);
+        self.follow_mouse.push(entity.follow_mouse);
+        self.follow_strengths.push(entity.follow_strength);
+        self.spawn_orders.push(self.next_spawn_order);
+        self.next_spawn_order += 1;
+    }
+
+    /// Puts the entity in the slot of `idx`, it counts as just spawned
+    pub fn replace(&mut self, idx: usize, entity: Entity) {
+        self.positions[idx] = entity.position;
+        self.velocities[idx] = entity.velocity;
+        self.forces[idx] = Vec2::ZERO;
+        self.radii[idx] = entity.radius;
+        self.bodies[idx] = entity.body;
+        self.is_colliding[idx] = entity.is_colliding;
+        self.collision_counts[idx] = entity.collision_count;
+        self.stuck_steps[idx] = entity.stuck_steps;
+        self.follow_mouse[idx] = entity.follow_mouse;
+        self.follow_strengths[idx] = entity.follow_strength;
+        self.spawn_orders[idx] = self.next_spawn_order;
+        self.next_spawn_order += 1;
+    }
+
+    /// Oldest entity that isn't a mouse follower or `keep`, none if there is no such entity
+    pub fn oldest_recyclable(&self, keep: Option<usize>) -> Option<usize> {
+        (0..self.len())
+            .filter(|&id| !self.follow_mouse[id] && Some(id) != keep)
+            .min_by_key(|&id| self.spawn_orders[id])
+    }
+
+    /// Copies the components of every entity back into spawn bundles
+    pub fn to_vec(&self) -> Vec<Entity> {
+        (0..self.len())
+            .map(|i| Entity {
+                position: self.positions[i],
+                velocity: self.velocities[i],
+                radius: self.radii[i],
+                body: self.bodies[i].clone(),
+                is_colliding: self.is_colliding[i],
+                collision_count: self.collision_counts[i],
+                stuck_steps: self.stuck_steps[i],
+                follow_mouse: self.follow_mouse[i],
+                follow_strength: self.follow_strengths[i],
+            })
+            .collect()
+    }
+
+    /// Moves the entities so the one at `order[i]` ends at `i`
+    fn reorder(&mut self, order: &[usize]) {
+        reorder(&mut self.positions, order);
+        reorder(&mut self.velocities, order);
+        reorder(&mut self.forces, order);
+        reorder(&mut self.radii, order);
+        reorder(&mut self.bodies, order);
+        reorder(&mut self.is_colliding, order);
+        reorder(&mut self.collision_counts, order);
+        reorder(&mut self.stuck_steps, order);
+        reorder(&mut self.follow_mouse, order);
+        reorder(&mut self.follow_strengths, order);
+        reorder(&mut self.spawn_orders, order);
+    }
+
+    /// Removes the entity swapping the last one into its slot
+    fn swap_remove(&mut self, idx: usize) {
+        self.positions.swap_remove(idx);
+        self.velocities.swap_remove(idx);
+        self.forces.swap_remove(idx);
+        self.radii.swap_remove(idx);
+        self.bodies.swap_remove(idx);
+        self.is_colliding.swap_remove(idx);
+        self.collision_counts.swap_remove(idx);
+        self.stuck_steps.swap_remove(idx);
+        self.follow_mouse.swap_remove(idx);
+        self.follow_strengths.swap_remove(idx);
+        self.spawn_orders.swap_remove(idx);
+    }
+}
+
+/// Moves the items so the one at `order[i]` ends at `i`, used to keep component arrays
+/// outside `Entities` in step with `World::sort_by_morton`
+pub fn reorder<T>(items: &mut Vec<T>, order: &[usize]) {
+    let mut old: Vec<Option<T>> = std::mem::take(items).into_iter().map(Some).collect();
+    *items = order.iter().map(|&id| old[id].take().unwrap()).collect();
+}
+
+impl FromIterator<Entity> for Entities {
+    fn from_iter<I: IntoIterator<Item = Entity>>(iter: I) -> Self {
+        let mut entities = Entities::default();
+        iter.into_iter().for_each(|e| entities.push(e));
+        entities
+    }
+}
+
+/// What happens to the bodies reaching the edges of the world
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BoundaryMode {
+    /// The bodies bounce against the edges, moved back inside if they crossed them
+    Bounce,
+    /// The edges are four static rects colliding like the obstacles, see `arena_walls`
+    Walls,
+    /// Leaving through one edge enters through the opposite one
+    Wrap,
+}
+
+/// How the velocities of new entities are drawn, picked with
+/// `INITIAL_VELOCITY_DISTRIBUTION`
+#[allow(dead_code)] // only the picked variant is built
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum VelocityDistribution {
+    /// Each axis uniform in `-INITIAL_SPEED..INITIAL_SPEED`, so speeds go from zero to
+    /// `INITIAL_SPEED * √2` along the diagonals
+    Uniform,
+    /// Random direction, every entity moves at exactly `INITIAL_SPEED`
+    FixedSpeed,
+}
+
+/// How `sys_resolve_collisions` solves the colliding pairs
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resolver {
+    /// Every pair in parallel against the same snapshot, each body gets the sum of its
+    /// responses
+    Jacobi,
+    /// Pairs solved one after another in parallel regions that can't share bodies, see
+    /// `region_sets`
+    Islands,
+    /// Same order and result as `Islands` on a single thread, to check it against
+    Sequential,
+}
+
+/// Strategy used to find the candidate pairs for the narrow phase
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BroadphaseKind {
+    /// Every body is a candidate of every other one
+    BruteForce,
+    /// Uniform grid of `cell_size` cells, see `SpatialGrid`
+    Grid,
+    /// Cells split while they hold too many bodies, see `Quadtree`
+    Quadtree,
+    /// Bodies that fit a grid cell in the grid, bigger ones in a coarse grid. Queries
+    /// check the big ones by their box, so the cells a big body covers don't make it a
+    /// candidate of every small body in them.
+    TwoTier,
+}
+
+/// Uniform grid where each cell keeps the ids of the bodies overlapping it
+pub struct SpatialGrid {
+    /// Width and height of a cell
+    pub cell_size: f32,
+    /// Cells along the x axis
+    pub cols: usize,
+    /// Cells along the y axis
+    pub rows: usize,
+    /// Ids of the bodies in each cell, row by row
+    pub cells: Vec<Vec<usize>>,
+}
+
+impl SpatialGrid {
+    fn new(cell_size: f32, width: f32, height: f32) -> Self {
+        let cols = (width / cell_size).ceil().max(1.0) as usize;
+        let rows = (height / cell_size).ceil().max(1.0) as usize;
+        Self {
+            cell_size,
+            cols,
+            rows,
+            cells: vec![vec![]; cols * rows],
+        }
+    }
+
+    /// Empties the cells keeping their allocations for the next frame
+    fn clear(&mut self) {
+        self.cells.iter_mut().for_each(Vec::clear);
+    }
+
+    /// Cells covered by the box, anything outside the grid goes to the border cells
+    fn cell_range(&self, min: Vec2, max: Vec2) -> ([usize; 2], [usize; 2]) {
+        let to_cell = |value: f32, count: usize| {
+            ((value / self.cell_size).floor().max(0.0) as usize).min(count - 1)
+        };
+        (
+            [to_cell(min.x, self.cols), to_cell(max.x, self.cols)],
+            [to_cell(min.y, self.rows), to_cell(max.y, self.rows)],
+        )
+    }
+
+    fn insert(&mut self, id: usize, min: Vec2, max: Vec2) {
+        let ([x1, x2], [y1, y2]) = self.cell_range(min, max);
+        for y in y1..=y2 {
+            for x in x1..=x2 {
+                self.cells[y * self.cols + x].push(id);
+            }
+        }
+    }
+
+    /// Fills `out` with the sorted ids stored in the cells covered by the box
+    fn query(&self, min: Vec2, max: Vec2, out: &mut Vec<usize>) {
+        out.clear();
+        out.extend(self.ids_in(min, max));
+        out.sort_unstable();
+        out.dedup();
+    }
+
+    /// Ids stored in the cells covered by the box, repeated for bodies in several of them
+    fn ids_in(&self, min: Vec2, max: Vec2) -> impl Iterator<Item = usize> + '_ {
+        let ([x1, x2], [y1, y2]) = self.cell_range(min, max);
+        (y1..=y2)
+            .flat_map(move |y| (x1..=x2).map(move |x| y * self.cols + x))
+            .flat_map(|cell| self.cells[cell].iter().copied())
+    }
+}
+
+struct QuadNode {
+    min: Vec2,
+    max: Vec2,
+    /// Bodies that don't fit entirely inside one of the children
+    items: Vec<(usize, Vec2, Vec2)>,
+    /// Index of the first of the four children in `Quadtree::nodes`
+    children: Option<usize>,
+}
+
+impl QuadNode {
+    fn new(min: Vec2, max: Vec2) -> Self {
+        Self {
+            min,
+            max,
+            items: vec![],
+            children: None,
+        }
+    }
+
+    fn contains(&self, min: Vec2, max: Vec2) -> bool {
+        min.x >= self.min.x && min.y >= self.min.y && max.x <= self.max.x && max.y <= self.max.y
+    }
+}
+
+/// Quadtree over the game area, bodies outside of it are kept in the root
+struct Quadtree {
+    nodes: Vec<QuadNode>,
+}
+
+impl Quadtree {
+    fn new(width: f32, height: f32) -> Self {
+        Self {
+            nodes: vec![QuadNode::new(Vec2::ZERO, vec2(width, height))],
+        }
+    }
+
+    /// Drops every node but the root
+    fn clear(&mut self) {
+        self.nodes.truncate(1);
+        self.nodes[0].items.clear();
+        self.nodes[0].children = None;
+    }
+
+    /// Child of the node that fully contains the box, if any
+    fn child_containing(&self, node: usize, min: Vec2, max: Vec2) -> Option<usize> {
+        let first = self.nodes[node].children?;
+        (first..first + 4).find(|&child| self.nodes[child].contains(min, max))
+    }
+
+    fn insert(&mut self, id: usize, min: Vec2, max: Vec2) {
+        let mut node = 0;
+        let mut depth = 0;
+        while let Some(child) = self.child_containing(node, min, max) {
+            node = child;
+            depth += 1;
+        }
+
+        self.nodes[node].items.push((id, min, max));
+        let can_split = self.nodes[node].children.is_none() && depth < QUADTREE_MAX_DEPTH;
+        if can_split && self.nodes[node].items.len() > QUADTREE_NODE_CAPACITY {
+            self.split(node);
+        }
+    }
+
+    /// Creates the four children and moves down the items that fit in one of them
+    fn split(&mut self, node: usize) {
+        let QuadNode { min, max, .. } = self.nodes[node];
+        let center = (min + max) * 0.5;
+        let first = self.nodes.len();
+        self.nodes.extend([
+            QuadNode::new(min, center),
+            QuadNode::new(vec2(center.x, min.y), vec2(max.x, center.y)),
+            QuadNode::new(vec2(min.x, center.y), vec2(center.x, max.y)),
+            QuadNode::new(center, max),
+        ]);
+        self.nodes[node].children = Some(first);
+
+        let items = std::mem::take(&mut self.nodes[node].items);
+        items.into_iter().for_each(|item @ (_, min, max)| {
+            let target = self.child_containing(node, min, max).unwrap_or(node);
+            self.nodes[target].items.push(item);
+        });
+    }
+
+    /// Fills `out` with the sorted ids of the bodies whose box overlaps this one
+    fn query(&self, min: Vec2, max: Vec2, out: &mut Vec<usize>) {
+        out.clear();
+        self.query_node(0, min, max, out);
+        out.sort_unstable();
+    }
+
+    /// Recursive so the traversal needs no stack allocation, the depth is bounded by
+    /// `QUADTREE_MAX_DEPTH`
+    fn query_node(&self, node: usize, min: Vec2, max: Vec2, out: &mut Vec<usize>) {
+        let node = &self.nodes[node];
+        out.extend(
+            node.items
+                .iter()
+                .filter(|(_, b_min, b_max)| aabb_overlap(min, max, *b_min, *b_max))
+                .map(|(id, _, _)| *id),
+        );
+        if let Some(first) = node.children {
+            (first..first + 4)
+                .filter(|&child| {
+                    let child = &self.nodes[child];
+                    aabb_overlap(min, max, child.min, child.max)
+                })
+                .for_each(|child| self.query_node(child, min, max, out));
+        }
+    }
+}
+
+/// Bodies per cell of a coarse grid, only the centers are counted
+pub struct DensityGrid {
+    /// Cells along the x axis
+    pub cols: usize,
+    rows: usize,
+    /// Bodies counted in each cell, row by row
+    pub counts: Vec<u32>,
+}
+
+impl DensityGrid {
+    fn new(size: Vec2) -> Self {
+        let cols = (size.x / DENSITY_CELL_SIZE).ceil().max(1.0) as usize;
+        let rows = (size.y / DENSITY_CELL_SIZE).ceil().max(1.0) as usize;
+        Self {
+            cols,
+            rows,
+            counts: vec![0; cols * rows],
+        }
+    }
+
+    /// Counts one more body at `position`, outside of the grid goes to the border cells
+    pub fn add(&mut self, position: Vec2) {
+        let cell = (position / DENSITY_CELL_SIZE).max(Vec2::ZERO);
+        let x = (cell.x as usize).min(self.cols - 1);
+        let y = (cell.y as usize).min(self.rows - 1);
+        self.counts[y * self.cols + x] += 1;
+    }
+
+    /// Bodies counted in the cell containing `position`, zero outside of the grid
+    pub fn count_at(&self, position: Vec2) -> u32 {
+        if position.x < 0.0 || position.y < 0.0 {
+            return 0;
+        }
+        let x = (position.x / DENSITY_CELL_SIZE) as usize;
+        let y = (position.y / DENSITY_CELL_SIZE) as usize;
+        if x >= self.cols || y >= self.rows {
+            return 0;
+        }
+        self.counts[y * self.cols + x]
+    }
+}
+
+/// Pair of bodies solved by `sys_resolve_collisions`, the ids are the ones of that step as
+/// the Morton sort renumbers the entities
+pub struct ContactEvent {
+    /// Ids of the two bodies
+    pub ids: [usize; 2],
+    /// How deep they overlapped before being solved
+    pub penetration: f32,
+}
+
+/// Collision solved in a step, what is done about it is left to whoever drains
+/// `World::collision_events`
+pub struct CollisionEvent {
+    /// Id of the first body, the ids are the ones of that step
+    pub id1: usize,
+    /// Id of the second body
+    pub id2: usize,
+    /// Speed the bodies were approaching each other at along the normal
+    pub impact_speed: f32,
+    /// Contact point between the bodies
+    pub position: Vec2,
+}
+
+/// Contact drawn by the debug view, `normal` points from the second body to the first
+pub struct ContactPoint {
+    /// Midpoint of the overlap
+    pub point: Vec2,
+    /// Unit vector along the line between the centers
+    pub normal: Vec2,
+    /// How deep the bodies overlapped
+    pub penetration: f32,
+}
+
+/// Pairs handed to the narrow phase and pairs that were really colliding in the last step
+#[derive(Default)]
+pub struct BroadphaseStats {
+    /// Pairs the broadphase returned
+    pub candidate_pairs: usize,
+    /// Pairs that were overlapping
+    pub collision_pairs: usize,
+}
+
+/// Index over the bodies that finds the candidate pairs, rebuilt when they move enough
+pub struct Broadphase {
+    /// Index used, changing it rebuilds the index on the next query
+    pub kind: BroadphaseKind,
+    /// Fine grid, also drawn by the debug view
+    pub grid: SpatialGrid,
+    quadtree: Quadtree,
+    /// Bodies too big for a cell of `grid`, only filled for `BroadphaseKind::TwoTier`
+    coarse: SpatialGrid,
+    /// Kind the index was last built for, none if it has to be built again
+    built_kind: Option<BroadphaseKind>,
+    /// Position and radius of every body when the index was built
+    built_bodies: Vec<(Vec2, f32)>,
+    /// Boxes are inserted this much larger so the index stays valid while no body moved
+    /// or grew more than it
+    slack: f32,
+}
+
+impl Broadphase {
+    /// Empty index covering a world of `size`, built on the first query
+    pub fn new(kind: BroadphaseKind, cell_size: f32, size: Vec2) -> Self {
+        Self {
+            kind,
+            grid: SpatialGrid::new(cell_size, size.x, size.y),
+            quadtree: Quadtree::new(size.x, size.y),
+            coarse: SpatialGrid::new(cell_size * COARSE_CELL_SCALE, size.x, size.y),
+            built_kind: None,
+            built_bodies: vec![],
+            slack: cell_size * 0.5,
+        }
+    }
+
+    /// True if a body was added, removed or moved out of the slack since the last build
+    fn is_stale(&self, positions: &[Vec2], radii: &[f32]) -> bool {
+        self.built_kind != Some(self.kind)
+            || self.built_bodies.len() != positions.len()
+            || positions
+                .par_iter()
+                .zip(radii.par_iter())
+                .zip(self.built_bodies.par_iter())
+                .any(|((p, r), &(position, radius))| {
+                    p.distance(position) + (r - radius).abs() > self.slack
+                })
+    }
+
+    fn rebuild(&mut self, positions: &[Vec2], radii: &[f32]) {
+        if self.kind == BroadphaseKind::BruteForce || !self.is_stale(positions, radii) {
+            return;
+        }
+
+        self.built_kind = Some(self.kind);
+        self.built_bodies.clear();
+        self.built_bodies
+            .extend(positions.iter().copied().zip(radii.iter().copied()));
+
+        // the boxes are computed in parallel but inserted in id order to stay deterministic
+        let slack = Vec2::splat(self.slack);
+        let boxes: Vec<(Vec2, Vec2)> = positions
+            .par_iter()
+            .zip(radii.par_iter())
+            .map(|(&position, &radius)| {
+                let (min, max) = circle_aabb(position, radius);
+                (min - slack, max + slack)
+            })
+            .collect();
+        match self.kind {
+            BroadphaseKind::BruteForce => {}
+            BroadphaseKind::Grid => {
+                self.grid.clear();
+                boxes
+                    .into_iter()
+                    .enumerate()
+                    .for_each(|(id, (min, max))| self.grid.insert(id, min, max));
+            }
+            BroadphaseKind::Quadtree => {
+                self.quadtree.clear();
+                boxes
+                    .into_iter()
+                    .enumerate()
+                    .for_each(|(id, (min, max))| self.quadtree.insert(id, min, max));
+            }
+            BroadphaseKind::TwoTier => {
+                self.grid.clear();
+                self.coarse.clear();
+                let max_fine_radius = self.grid.cell_size * 0.5;
+                boxes.into_iter().enumerate().for_each(|(id, (min, max))| {
+                    if radii[id] > max_fine_radius {
+                        self.coarse.insert(id, min, max);
+                    } else {
+                        self.grid.insert(id, min, max);
+                    }
+                });
+            }
+        }
+    }
+
+    /// Fills `out` with the sorted ids of the bodies that may overlap the box
+    fn query(&self, positions: &[Vec2], radii: &[f32], min: Vec2, max: Vec2, out: &mut Vec<usize>) {
+        let overlaps = |id: usize| {
+            let (b_min, b_max) = circle_aabb(positions[id], radii[id]);
+            aabb_overlap(min, max, b_min, b_max)
+        };
+        match self.kind {
+            BroadphaseKind::BruteForce => {
+                out.clear();
+                out.extend((0..positions.len()).filter(|&id| overlaps(id)));
+            }
+            BroadphaseKind::Grid => self.grid.query(min, max, out),
+            BroadphaseKind::Quadtree => self.quadtree.query(min, max, out),
+            BroadphaseKind::TwoTier => {
+                self.grid.query(min, max, out);
+                // a big box covers many fine cells, it only keeps the bodies it overlaps
+                if (max - min).max_element() > self.grid.cell_size {
+                    out.retain(|&id| overlaps(id));
+                }
+                out.extend(self.coarse.ids_in(min, max).filter(|&id| overlaps(id)));
+                out.sort_unstable();
+                out.dedup();
+            }
+        }
+    }
+}
+
+/// Simulation state, independent of the window, the input and the rendering
+pub struct World {
+    /// Width and height of the world, the camera and the broadphase cover all of it
+    pub size: Vec2,
+    /// Distance from the edges of the world to the ones the bodies bounce against, see
+    /// `World::bounds`
+    pub margin: f32,
+    /// What the edges of the world do to the bodies reaching them
+    pub boundary: BoundaryMode,
+    /// Every body in the world, the ids are their indices
+    pub entities: Entities,
+    /// Finds the candidate pairs of each step
+    pub broadphase: Broadphase,
+    /// Pair counts of the last step
+    pub stats: BroadphaseStats,
+    /// Bodies the last step put back after they were left with non-finite values or far
+    /// outside the world, see `sys_reset_broken_bodies`
+    pub reset_count: usize,
+    /// Acceleration applied to every body that isn't following the mouse
+    pub gravity: Vec2,
+    /// Fraction of the speed kept bouncing against the edges and the obstacles
+    pub wall_restitution: f32,
+    /// Fraction of the tangential relative velocity removed when two bodies collide
+    pub friction: f32,
+    /// Fraction of the velocity lost per second, 0.0 keeps bodies moving forever
+    pub linear_damping: f32,
+    /// Immovable rectangles the bodies bounce against
+    pub obstacles: Vec<Rect>,
+    /// Point the mouse followers steer towards, none when there is no cursor
+    pub follow_target: Option<Vec2>,
+    /// Pulls every body towards `follow_target` when not zero
+    pub attract_strength: f32,
+    /// Bodies per cell, refreshed each step while `avoid_density` is on
+    pub density: DensityGrid,
+    /// Makes the mouse followers steer around crowded cells instead of plowing through
+    pub avoid_density: bool,
+    /// Contacts solved since they were last drained, only collected while `Some`
+    pub contact_events: Option<Vec<ContactEvent>>,
+    /// Collisions solved since they were last drained, only collected while `Some`
+    pub collision_events: Option<Vec<CollisionEvent>>,
+    /// Contacts solved in the last step, only collected while the debug view is on
+    pub contact_points: Option<Vec<ContactPoint>>,
+    /// How the colliding pairs are solved
+    pub resolver: Resolver,
+    /// Detect and resolve passes per step, more of them settle dense packs tighter
+    pub resolve_iterations: usize,
+    /// Lets bodies resting for `SLEEP_TIME` fall asleep, they skip gravity and the
+    /// integration until something wakes them. Off by default, turning it off wakes them all.
+    pub sleep: bool,
+}
+
+impl World {
+    /// Size of the world when bodies wrap around its edges, they collide across them too
+    pub fn wrap(&self) -> Option<Vec2> {
+        (self.boundary == BoundaryMode::Wrap).then_some(self.size)
+    }
+
+    /// Area the bodies bounce inside, the world inset by `margin`. Wrapping ignores the
+    /// margin, the bodies cross the edges of the whole world.
+    pub fn bounds(&self) -> Rect {
+        Rect {
+            x: self.margin,
+            y: self.margin,
+            width: self.size.x - self.margin * 2.0,
+            height: self.size.y - self.margin * 2.0,
+        }
+    }
+
+    /// Sorts the entities along the Z-order curve of their grid cell so bodies close in
+    /// space are close in memory. Ids change, returns the old id of each entity.
+    pub fn sort_by_morton(&mut self) -> Vec<usize> {
+        let cell_size = self.broadphase.grid.cell_size;
+        let positions = &self.entities.positions;
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by_cached_key(|&id| {
+            let cell = (positions[id] / cell_size).max(Vec2::ZERO);
+            morton_code(cell.x as u16, cell.y as u16)
+        });
+        self.entities.reorder(&order);
+        order
+    }
+
+    /// World of `size` holding the entities, `cell_size` is the side of the broadphase
+    /// grid cells and should fit the common bodies, see `grid_cell_size`
+    pub fn new(entities: Entities, cell_size: f32, size: Vec2) -> Self {
+        Self {
+            size,
+            margin: WORLD_MARGIN,
+            boundary: BoundaryMode::Bounce,
+            entities,
+            broadphase: Broadphase::new(BroadphaseKind::Grid, cell_size, size),
+            stats: BroadphaseStats::default(),
+            reset_count: 0,
+            gravity: Vec2::ZERO,
+            wall_restitution: 1.0,
+            friction: 0.0,
+            linear_damping: 0.0,
+            obstacles: default_obstacles(),
+            follow_target: None,
+            attract_strength: 0.0,
+            density: DensityGrid::new(size),
+            avoid_density: false,
+            contact_events: None,
+            collision_events: None,
+            contact_points: None,
+            resolver: Resolver::Jacobi,
+            resolve_iterations: 1,
+            sleep: false,
+        }
+    }
+
+    /// Advances the simulation one step of `delta` seconds, see `simulate`
+    pub fn step(&mut self, delta: f32) {
+        simulate(self, delta);
+    }
+
+    /// Adds the entity with the last id, returns it
+    pub fn spawn(&mut self, entity: Entity) -> usize {
+        self.entities.push(entity);
+        self.entities.len() - 1
+    }
+
+    /// Removes the entity moving the last one into its id
+    pub fn despawn(&mut self, id: usize) {
+        self.entities.swap_remove(id);
+    }
+
+    /// Sorted ids of the entities whose bounding box overlaps the region
+    pub fn query_region(&mut self, min: Vec2, max: Vec2) -> Vec<usize> {
+        let Entities {
+            positions, radii, ..
+        } = &self.entities;
+        self.broadphase.rebuild(positions, radii);
+
+        let mut candidates = vec![];
+        self.broadphase
+            .query(positions, radii, min, max, &mut candidates);
+        candidates.retain(|&id| {
+            let (b_min, b_max) = circle_aabb(positions[id], radii[id]);
+            aabb_overlap(min, max, b_min, b_max)
+        });
+        candidates
+    }
+
+    /// Entity whose pick circle contains the point, the one with the nearest center if
+    /// several overlap it. Bodies smaller than `MIN_PICK_RADIUS` are picked within it.
+    pub fn pick_entity(&mut self, position: Vec2) -> Option<usize> {
+        let reach = Vec2::splat(MIN_PICK_RADIUS);
+        let candidates = self.query_region(position - reach, position + reach);
+        let Entities {
+            positions, radii, ..
+        } = &self.entities;
+        candidates
+            .into_iter()
+            .map(|id| (id, positions[id].distance_squared(position)))
+            .filter(|&(id, distance)| distance <= radii[id].max(MIN_PICK_RADIUS).powi(2))
+            .min_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+            .map(|(id, _)| id)
+    }
+
+    /// Changes the radius of the entity, with the mass that goes with it
+    pub fn resize(&mut self, id: usize, radius: f32) {
+        self.entities.radii[id] = radius;
+        self.entities.bodies[id].mass = mass_from_radius(radius);
+    }
+
+    /// True if another body overlaps the entity deeper than `STUCK_OVERLAP_FRACTION` of
+    /// that body's radius, the depth at which it would count as stuck
+    pub fn is_crowded(&self, id: usize) -> bool {
+        let wrap = self.wrap();
+        let Entities {
+            positions, radii, ..
+        } = &self.entities;
+        let (position, radius) = (positions[id], radii[id]);
+        positions
+            .iter()
+            .zip(radii)
+            .enumerate()
+            .any(|(other, (&p, &r))| {
+                let distance = position.distance(nearest_image(position, p, wrap));
+                other != id && radius + r - distance > r * STUCK_OVERLAP_FRACTION
+            })
+    }
+
+    /// Adds the same velocity to every body whatever its mass, waking the asleep ones
+    pub fn apply_impulse(&mut self, impulse: Vec2) {
+        sys_apply_global_impulse(
+            &mut self.entities.velocities,
+            &mut self.entities.bodies,
+            impulse,
+        );
+    }
+
+    /// Counts the bodies per cell of `density` again, `step` only does it while
+    /// `avoid_density` is on
+    pub fn update_density(&mut self) {
+        sys_density_grid(&self.entities.positions, &mut self.density);
+    }
+
+    /// True if the broadphase finds the same colliding pairs as testing all of them. Slow,
+    /// only meant to check the broadphases.
+    pub fn check_broadphase(&mut self) -> bool {
+        let wrap = self.wrap();
+        let Entities {
+            positions,
+            radii,
+            bodies,
+            ..
+        } = &self.entities;
+        let found = sys_check_collision(
+            positions,
+            radii,
+            bodies,
+            &mut self.broadphase,
+            &mut BroadphaseStats::default(),
+            wrap,
+        );
+        found == brute_force_collisions(positions, radii, bodies, wrap)
+    }
+
+    /// Hash of the exact bits of every position and velocity, runs that agree on it ended
+    /// with the same bodies
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.entities
+            .positions
+            .iter()
+            .zip(&self.entities.velocities)
+            .for_each(|(position, velocity)| {
+                [position, velocity]
+                    .iter()
+                    .flat_map(|v| v.to_array())
+                    .for_each(|value| value.to_bits().hash(&mut hasher));
+            });
+        hasher.finish()
+    }
+
+    /// Total kinetic energy and magnitude of the total momentum of the bodies
+    pub fn energy(&self) -> (f32, f32) {
+        let (energy, momentum) = self
+            .entities
+            .velocities
+            .iter()
+            .zip(&self.entities.bodies)
+            .fold((0.0, Vec2::ZERO), |(energy, momentum), (&velocity, b)| {
+                (
+                    energy + 0.5 * b.mass * velocity.length_squared(),
+                    momentum + velocity * b.mass,
+                )
+            });
+        (energy, momentum.length())
+    }
+}
+
+/// Accumulates the forces of this step, the order doesn't matter as they are only added
+fn apply_forces(world: &mut World) {
+    let entities = &mut world.entities;
+    if let Some(target) = world.follow_target {
+        sys_follow_mouse(
+            &entities.positions,
+            &entities.velocities,
+            &mut entities.forces,
+            &entities.bodies,
+            &entities.follow_mouse,
+            &entities.follow_strengths,
+            target,
+        );
+        if world.avoid_density {
+            sys_density_grid(&entities.positions, &mut world.density);
+            sys_avoid_density(
+                &entities.positions,
+                &entities.radii,
+                &mut entities.forces,
+                &entities.bodies,
+                &entities.follow_mouse,
+                &world.density,
+                target,
+            );
+        }
+        if world.attract_strength != 0.0 {
+            sys_attract_to_point(
+                &entities.positions,
+                &mut entities.forces,
+                &entities.bodies,
+                target,
+                world.attract_strength,
+            );
+        }
+    }
+    sys_apply_gravity(
+        &mut entities.forces,
+        &entities.bodies,
+        &entities.follow_mouse,
+        world.gravity,
+    );
+}
+
+/// Advances the simulation one step of `delta` seconds.
+/// The same world and `delta` always give bit-identical bodies, whatever the thread count:
+/// the parallel systems only work per body or per pair and every sum over several pairs
+/// runs in pair order on one thread. `state_hash` checks it.
+fn simulate(world: &mut World, delta: f32) {
+    if let Some(points) = &mut world.contact_points {
+        points.clear();
+    }
+    let entities = &mut world.entities;
+    sys_clean_collisions(&mut entities.is_colliding, &mut entities.collision_counts);
+
+    // -- forces
+    // every system adding to `Entities::forces` goes in `apply_forces`, the integrator right
+    // after it is the only one consuming and clearing the force
+    apply_forces(world);
+    let bounds = world.bounds();
+    let entities = &mut world.entities;
+    let walls = arena_walls(&bounds);
+    let constrain = |position: &mut Vec2, velocity: &mut Vec2, radius: f32, b: &mut Body| {
+        let restitution = world.wall_restitution * b.restitution;
+        match world.boundary {
+            BoundaryMode::Bounce => bounce_body(position, velocity, radius, &bounds, restitution),
+            BoundaryMode::Walls => {
+                collide_body_static_rects(position, velocity, radius, &walls, restitution)
+            }
+            BoundaryMode::Wrap => wrap_body(position, &mut b.last_position, world.size),
+        }
+        collide_body_static_rects(position, velocity, radius, &world.obstacles, restitution);
+    };
+    sys_apply_movement_to_body(
+        &mut entities.positions,
+        &mut entities.velocities,
+        &mut entities.forces,
+        &entities.radii,
+        &mut entities.bodies,
+        delta,
+        constrain,
+    );
+    sys_apply_rotation(&mut entities.bodies, delta);
+
+    // -- constraints and collisions, these work on positions and velocities only
+    sys_apply_damping(&mut entities.velocities, world.linear_damping, delta);
+    world.reset_count = sys_reset_broken_bodies(
+        &mut entities.positions,
+        &mut entities.velocities,
+        &mut entities.forces,
+        &entities.radii,
+        &mut entities.bodies,
+        world.size,
+    );
+    let wrap = world.wrap();
+    let entities = &mut world.entities;
+    let mut collisions = sys_check_collision(
+        &entities.positions,
+        &entities.radii,
+        &entities.bodies,
+        &mut world.broadphase,
+        &mut world.stats,
+        wrap,
+    );
+    sys_mark_collisions(
+        &mut entities.is_colliding,
+        &mut entities.collision_counts,
+        &collisions,
+    );
+    sys_skip_resting_pairs(&mut collisions, &entities.bodies);
+    sys_resolve_collisions(
+        &mut entities.positions,
+        &mut entities.velocities,
+        &entities.radii,
+        &mut entities.bodies,
+        &collisions,
+        world.friction,
+        world.resolver,
+        wrap,
+        world.contact_events.as_mut(),
+        world.collision_events.as_mut(),
+        world.contact_points.as_mut(),
+    );
+    // the extra passes only push the bodies further apart, the contacts, flashes and
+    // stats of the step come from the first one
+    (1..world.resolve_iterations).for_each(|_| {
+        collisions = sys_check_collision(
+            &entities.positions,
+            &entities.radii,
+            &entities.bodies,
+            &mut world.broadphase,
+            &mut BroadphaseStats::default(),
+            wrap,
+        );
+        sys_skip_resting_pairs(&mut collisions, &entities.bodies);
+        sys_resolve_collisions(
+            &mut entities.positions,
+            &mut entities.velocities,
+            &entities.radii,
+            &mut entities.bodies,
+            &collisions,
+            world.friction,
+            world.resolver,
+            wrap,
+            None,
+            None,
+            None,
+        );
+    });
+    sys_track_stuck(
+        &entities.positions,
+        &entities.radii,
+        &mut entities.stuck_steps,
+        &collisions,
+        wrap,
+    );
+    if world.sleep {
+        // walls only hold the bodies up where they are solid, the wrapping edges don't
+        let edges = (world.boundary != BoundaryMode::Wrap).then_some(bounds);
+        let obstacles = &world.obstacles;
+        sys_update_sleep(
+            &entities.positions,
+            &mut entities.velocities,
+            &entities.radii,
+            &mut entities.bodies,
+            &entities.collision_counts,
+            delta,
+            |position, radius| touches_static(position, radius, edges.as_ref(), obstacles),
+        );
+    } else {
+        entities
+            .bodies
+            .iter_mut()
+            .filter(|b| b.asleep)
+            .for_each(Body::wake);
+    }
+}
+
+/// `count` entities with random positions, radii and velocities inside `size`
+pub fn init_entities(
+    rng: &mut Random,
+    count: usize,
+    min_radius: f32,
+    max_radius: f32,
+    size: Vec2,
+) -> Entities {
+    (0..count)
+        .map(|_| {
+            let radius = min_radius + rng.gen::<f32>() * (max_radius - min_radius);
+            random_entity(rng, radius, size)
+        })
+        .collect()
+}
+
+/// Static rects every world starts with
+pub fn default_obstacles() -> Vec<Rect> {
+    vec![
+        Rect {
+            x: 180.0,
+            y: 240.0,
+            width: 160.0,
+            height: 30.0,
+        },
+        Rect {
+            x: 520.0,
+            y: 360.0,
+            width: 40.0,
+            height: 160.0,
+        },
+    ]
+}
+
+/// Entities of `ENTITY_RADIUS` at rest on a lattice of `cols` by `rows` centered in the
+/// world, `spacing` apart. The spacing is raised to `LATTICE_MIN_SPACING` so they never
+/// start overlapping. Cells outside the world or touching one of the `obstacles` are
+/// left empty.
+pub fn init_entities_grid(
+    cols: usize,
+    rows: usize,
+    spacing: f32,
+    size: Vec2,
+    obstacles: &[Rect],
+) -> Entities {
+    let spacing = spacing.max(LATTICE_MIN_SPACING);
+    let extent = vec2(cols.saturating_sub(1) as f32, rows.saturating_sub(1) as f32) * spacing;
+    let origin = (size - extent) * 0.5;
+    (0..rows)
+        .flat_map(|row| (0..cols).map(move |col| vec2(col as f32, row as f32)))
+        .map(|cell| origin + cell * spacing)
+        .filter(|&position| {
+            let radius = Vec2::splat(ENTITY_RADIUS);
+            position.cmpge(radius).all()
+                && position.cmple(size - radius).all()
+                && obstacles
+                    .iter()
+                    .all(|rect| circle_rect_contact(position, ENTITY_RADIUS, rect).is_none())
+        })
+        .map(|position| create_entity(position, Vec2::ZERO, ENTITY_RADIUS))
+        .collect()
+}
+
+/// Entity at a random position inside the game area with a random velocity
+pub fn random_entity(rng: &mut Random, radius: f32, size: Vec2) -> Entity {
+    let min_pos = vec2(50.0, 50.0);
+    let max_pos = size - min_pos * 2.0;
+    let position = vec2(
+        min_pos.x + rng.gen::<f32>() * max_pos.x,
+        min_pos.y + rng.gen::<f32>() * max_pos.y,
+    );
+    let velocity = random_velocity(rng);
+    create_entity(position, velocity, radius)
+}
+
+/// Velocity drawn from `INITIAL_VELOCITY_DISTRIBUTION`, both are centered on zero so the
+/// entities have no preferred direction
+pub fn random_velocity(rng: &mut Random) -> Vec2 {
+    match INITIAL_VELOCITY_DISTRIBUTION {
+        VelocityDistribution::Uniform => vec2(
+            -INITIAL_SPEED + rng.gen::<f32>() * INITIAL_SPEED * 2.0,
+            -INITIAL_SPEED + rng.gen::<f32>() * INITIAL_SPEED * 2.0,
+        ),
+        VelocityDistribution::FixedSpeed => {
+            Vec2::from_angle(rng.gen::<f32>() * TAU) * INITIAL_SPEED
+        }
+    }
+}
+
+/// Entity with the default components and a body of the given radius
+pub fn create_entity(position: Vec2, velocity: Vec2, radius: f32) -> Entity {
+    Entity {
+        position,
+        velocity,
+        radius,
+        body: Body {
+            last_position: position,
+            mass: mass_from_radius(radius),
+            layer: ALL_LAYERS,
+            mask: ALL_LAYERS,
+            restitution: RESTITUTION,
+            sleep_time: 0.0,
+            asleep: false,
+            angular_velocity: 0.0,
+            rotation: 0.0,
+        },
+        is_colliding: false,
+        collision_count: 0,
+        stuck_steps: 0,
+        follow_mouse: false,
+        follow_strength: FOLLOW_STEERING_GAIN,
+    }
+}
+
+/// Grid cells fit one entity of the given radius
+pub fn grid_cell_size(radius: f32) -> f32 {
+    radius * 2.0
+}
+
+/// Mass of a circle with uniform density, proportional to its area
+fn mass_from_radius(radius: f32) -> f32 {
+    radius * radius * PI
+}
+
+/// Both bodies must accept the layer of the other one
+fn can_collide(b1: &Body, b2: &Body) -> bool {
+    (b1.mask & b2.layer) != 0 && (b2.mask & b1.layer) != 0
+}
+
+/// No per-axis early-out before the squared distance, the brute force and quadtree
+/// candidates already overlap on both axes and with the grid ones it was slower in dense
+/// scenes and no faster in sparse ones
+fn is_colliding(p1: Vec2, r1: f32, p2: Vec2, r2: f32) -> bool {
+    let sum_radius = r1 + r2;
+    let square_radius = sum_radius * sum_radius;
+    let square_distance = p1.distance_squared(p2);
+    square_distance <= square_radius
+}
+
+/// How two shapes overlap, `normal` is the unit direction that pushes the first one out
+struct Contact {
+    normal: Vec2,
+    penetration: f32,
+}
+
+/// Contact of the first circle against the second, `None` if they don't overlap.
+/// Touching circles give a zero penetration. Coincident centers have no direction so
+/// the first circle is pushed along +x.
+fn contact(p1: Vec2, r1: f32, p2: Vec2, r2: f32) -> Option<Contact> {
+    if !is_colliding(p1, r1, p2, r2) {
+        return None;
+    }
+
+    let delta = p1 - p2;
+    let distance = delta.length();
+    let normal = if distance > MIN_CONTACT_DISTANCE {
+        delta / distance
+    } else {
+        Vec2::X
+    };
+    Some(Contact {
+        normal,
+        penetration: r1 + r2 - distance,
+    })
+}
+
+/// Contact of the circle against the rect, the normal points out of the rect
+fn circle_rect_contact(center: Vec2, radius: f32, rect: &Rect) -> Option<Contact> {
+    let min = vec2(rect.min_x(), rect.min_y());
+    let max = vec2(rect.max_x(), rect.max_y());
+    let closest = center.clamp(min, max);
+    let delta = center - closest;
+    let distance_squared = delta.length_squared();
+    if distance_squared > 0.0 {
+        if distance_squared > radius * radius {
+            return None;
+        }
+
+        let distance = distance_squared.sqrt();
+        return Some(Contact {
+            normal: delta / distance,
+            penetration: radius - distance,
+        });
+    }
+
+    // the center is inside the rect, push it out through the nearest side
+    [
+        (-Vec2::X, center.x - min.x),
+        (Vec2::X, max.x - center.x),
+        (-Vec2::Y, center.y - min.y),
+        (Vec2::Y, max.y - center.y),
+    ]
+    .into_iter()
+    .min_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+    .map(|(normal, distance)| Contact {
+        normal,
+        penetration: distance + radius,
+    })
+}
+
+/// Bounding box of the circle as its min and max corners
+pub fn circle_aabb(position: Vec2, radius: f32) -> (Vec2, Vec2) {
+    let radius = Vec2::splat(radius);
+    (position - radius, position + radius)
+}
+
+/// Interleaves the bits of both coordinates, `x` in the even bits and `y` in the odd ones
+fn morton_code(x: u16, y: u16) -> u32 {
+    let spread = |value: u16| {
+        let mut v = value as u32;
+        v = (v | (v << 8)) & 0x00FF_00FF;
+        v = (v | (v << 4)) & 0x0F0F_0F0F;
+        v = (v | (v << 2)) & 0x3333_3333;
+        (v | (v << 1)) & 0x5555_5555
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+fn aabb_overlap(min1: Vec2, max1: Vec2, min2: Vec2, max2: Vec2) -> bool {
+    min1.x <= max2.x && max1.x >= min2.x && min1.y <= max2.y && max1.y >= min2.y
+}
+
+// systems
+fn sys_clean_collisions(is_colliding: &mut [bool], collision_counts: &mut [u32]) {
+    is_colliding.fill(false);
+    collision_counts.fill(0);
+}
+
+/// Puts back the bodies a solver explosion left with NaN or infinite values, or more than
+/// the world size away from it, so they can't spread to the broadphase and the other
+/// bodies. Returns how many were reset.
+fn sys_reset_broken_bodies(
+    positions: &mut [Vec2],
+    velocities: &mut [Vec2],
+    forces: &mut [Vec2],
+    radii: &[f32],
+    bodies: &mut [Body],
+    size: Vec2,
+) -> usize {
+    let is_broken = |position: Vec2, velocity: Vec2, b: &Body| {
+        !position.is_finite()
+            || !velocity.is_finite()
+            || !b.angular_velocity.is_finite()
+            || !b.rotation.is_finite()
+            || position.cmplt(-size).any()
+            || position.cmpgt(size * 2.0).any()
+    };
+    positions
+        .iter_mut()
+        .zip(velocities.iter_mut())
+        .zip(forces.iter_mut().zip(radii))
+        .zip(bodies.iter_mut())
+        .filter(|(((position, velocity), _), b)| is_broken(**position, **velocity, b))
+        .map(|(((position, velocity), (force, &radius)), b)| {
+            *position = if position.is_finite() {
+                position.max(Vec2::splat(radius)).min(size - radius)
+            } else {
+                size * 0.5
+            };
+            b.last_position = *position;
+            *velocity = Vec2::ZERO;
+            *force = Vec2::ZERO;
+            b.angular_velocity = 0.0;
+            b.rotation = 0.0;
+        })
+        .count()
+}
+
+/// Candidate pairs from the broadphase that really collide. `wrap` is the world size when
+/// it wraps around, then bodies also collide across the edges.
+fn sys_check_collision(
+    positions: &[Vec2],
+    radii: &[f32],
+    bodies: &[Body],
+    broadphase: &mut Broadphase,
+    stats: &mut BroadphaseStats,
+    wrap: Option<Vec2>,
+) -> Vec<Collision> {
+    broadphase.rebuild(positions, radii);
+    let broadphase = &*broadphase;
+
+    // every body queries on its own with a scratch buffer per thread, the results are
+    // collected in id order so the pairs don't depend on the thread count
+    let found: Vec<(usize, Vec<Collision>)> = (0..positions.len())
+        .into_par_iter()
+        .map_init(Vec::new, |candidates, id1| {
+            let (min, max) = circle_aabb(positions[id1], radii[id1]);
+            broadphase.query(positions, radii, min, max, candidates);
+
+            // only check the bodies after this one so each pair is reported once
+            let later = candidates.iter().filter(|&&id2| id2 > id1);
+            let colliding = later
+                .clone()
+                .filter(|&&id2| pair_collides(positions, radii, bodies, [id1, id2], wrap))
+                .map(|&id2| Collision([id1, id2]))
+                .collect();
+            (later.count(), colliding)
+        })
+        .collect();
+    stats.candidate_pairs = found.iter().map(|(count, _)| count).sum();
+    let mut colliding: Vec<Collision> = found.into_iter().flat_map(|(_, pairs)| pairs).collect();
+
+    // the index only holds the real boxes, bodies near an edge look for the ones near the
+    // opposite edge with their box moved across. Every pair across the seam has one body
+    // within its diameter of the edge it crosses, so that one always finds the other.
+    if let Some(size) = wrap {
+        let margin = radii.iter().copied().fold(0.0, f32::max) * 2.0;
+        let found: Vec<(usize, Vec<Collision>)> = (0..positions.len())
+            .into_par_iter()
+            .map_init(Vec::new, |candidates, id1| {
+                let (min, max) = circle_aabb(positions[id1], radii[id1]);
+                let mut count = 0;
+                let mut colliding = vec![];
+                seam_shifts(min, max, size, margin)
+                    .into_iter()
+                    .for_each(|shift| {
+                        broadphase.query(positions, radii, min + shift, max + shift, candidates);
+                        candidates
+                            .iter()
+                            .filter(|&&id2| id2 != id1)
+                            .for_each(|&id2| {
+                                count += 1;
+                                let ids = [id1.min(id2), id1.max(id2)];
+                                if pair_collides(positions, radii, bodies, ids, wrap) {
+                                    colliding.push(Collision(ids));
+                                }
+                            });
+                    });
+                (count, colliding)
+            })
+            .collect();
+        stats.candidate_pairs += found.iter().map(|(count, _)| count).sum::<usize>();
+        colliding.extend(found.into_iter().flat_map(|(_, pairs)| pairs));
+        // both bodies can find a pair across the seam, and in a world smaller than a body
+        // the direct query finds it too
+        colliding.sort_unstable_by_key(|&Collision(ids)| ids);
+        colliding.dedup();
+    }
+
+    stats.collision_pairs = colliding.len();
+    colliding
+}
+
+/// Offsets that move a box within `margin` of the edges of a wrapping world of `size` to
+/// the opposite side, including the diagonal one near a corner
+fn seam_shifts(min: Vec2, max: Vec2, size: Vec2, margin: f32) -> Vec<Vec2> {
+    let axis = |min: f32, max: f32, size: f32| {
+        let mut shifts = vec![0.0];
+        if min < margin {
+            shifts.push(size);
+        }
+        if max > size - margin {
+            shifts.push(-size);
+        }
+        shifts
+    };
+    let xs = axis(min.x, max.x, size.x);
+    let ys = axis(min.y, max.y, size.y);
+    xs.iter()
+        .flat_map(|&x| ys.iter().map(move |&y| vec2(x, y)))
+        .filter(|&shift| shift != Vec2::ZERO)
+        .collect()
+}
+
+/// `p2` moved by whole world sizes to the copy nearest to `p1` when the world wraps,
+/// unchanged when it doesn't or the nearest copy is `p2` itself
+fn nearest_image(p1: Vec2, p2: Vec2, wrap: Option<Vec2>) -> Vec2 {
+    match wrap {
+        Some(size) => p2 - ((p2 - p1) / size).round() * size,
+        None => p2,
+    }
+}
+
+/// Counts the steps in a row each body is left overlapping another one by more than
+/// `STUCK_OVERLAP_FRACTION` of the smaller radius after the resolution. Resting contacts
+/// stay well below that, a count that keeps growing means the resolver isn't separating
+/// the pair.
+fn sys_track_stuck(
+    positions: &[Vec2],
+    radii: &[f32],
+    stuck_steps: &mut [u32],
+    collisions: &[Collision],
+    wrap: Option<Vec2>,
+) {
+    let mut deep = vec![false; positions.len()];
+    collisions.iter().for_each(|&Collision([id1, id2])| {
+        let limit = radii[id1].min(radii[id2]) * STUCK_OVERLAP_FRACTION;
+        let p2 = nearest_image(positions[id1], positions[id2], wrap);
+        if contact(positions[id1], radii[id1], p2, radii[id2])
+            .is_some_and(|contact| contact.penetration > limit)
+        {
+            deep[id1] = true;
+            deep[id2] = true;
+        }
+    });
+    stuck_steps
+        .iter_mut()
+        .zip(deep)
+        .for_each(|(steps, deep)| *steps = if deep { *steps + 1 } else { 0 });
+}
+
+/// Narrow phase test of a candidate pair, the components of the bodies are only read once
+/// their circles overlap
+fn pair_collides(
+    positions: &[Vec2],
+    radii: &[f32],
+    bodies: &[Body],
+    [id1, id2]: [usize; 2],
+    wrap: Option<Vec2>,
+) -> bool {
+    let p1 = positions[id1];
+    is_colliding(
+        p1,
+        radii[id1],
+        nearest_image(p1, positions[id2], wrap),
+        radii[id2],
+    ) && can_collide(&bodies[id1], &bodies[id2])
+}
+
+/// Every colliding pair found by testing all of them, in the order `sys_check_collision`
+/// reports them. Reference for checking the broadphases.
+fn brute_force_collisions(
+    positions: &[Vec2],
+    radii: &[f32],
+    bodies: &[Body],
+    wrap: Option<Vec2>,
+) -> Vec<Collision> {
+    let count = positions.len();
+    (0..count)
+        .flat_map(|id1| (id1 + 1..count).map(move |id2| [id1, id2]))
+        .filter(|&ids| pair_collides(positions, radii, bodies, ids, wrap))
+        .map(Collision)
+        .collect()
+}
+
+/// Pairs of asleep bodies are counted as contacts but not solved, resting piles stay asleep
+fn sys_skip_resting_pairs(collisions: &mut Vec<Collision>, bodies: &[Body]) {
+    collisions.retain(|Collision([id1, id2])| !(bodies[*id1].asleep && bodies[*id2].asleep));
+}
+
+fn sys_mark_collisions(
+    is_colliding: &mut [bool],
+    collision_counts: &mut [u32],
+    collisions: &[Collision],
+) {
+    collisions.iter().for_each(|Collision(ids)| {
+        ids.iter().for_each(|&id| {
+            is_colliding[id] = true;
+            collision_counts[id] += 1;
+        });
+    });
+}
+
+/// `wrap` is the world size when it wraps around, pairs across the edges are solved
+/// against the nearest copy of the second body
+#[allow(clippy::too_many_arguments)]
+fn sys_resolve_collisions(
+    positions: &mut [Vec2],
+    velocities: &mut [Vec2],
+    radii: &[f32],
+    bodies: &mut [Body],
+    collisions: &[Collision],
+    friction: f32,
+    resolver: Resolver,
+    wrap: Option<Vec2>,
+    mut contact_events: Option<&mut Vec<ContactEvent>>,
+    mut collision_events: Option<&mut Vec<CollisionEvent>>,
+    mut contact_points: Option<&mut Vec<ContactPoint>>,
+) {
+    let responses = match resolver {
+        Resolver::Jacobi => solve_jacobi(
+            positions, velocities, radii, bodies, collisions, friction, wrap,
+        ),
+        Resolver::Islands | Resolver::Sequential => {
+            let mut solver_bodies: Vec<SolverBody> = (0..positions.len())
+                .map(|id| SolverBody::gather(positions, velocities, radii, bodies, id))
+                .collect();
+            let parallel = resolver == Resolver::Islands;
+            let responses = solve_regions(&mut solver_bodies, collisions, friction, wrap, parallel);
+            solver_bodies
+                .into_iter()
+                .enumerate()
+                .for_each(|(id, b)| b.scatter(positions, velocities, bodies, id));
+            responses
+        }
+    };
+
+    collisions
+        .iter()
+        .zip(responses)
+        .filter_map(|(collision, response)| Some((collision, response?)))
+        .for_each(|(Collision(ids), response)| {
+            if let Some(events) = contact_events.as_deref_mut() {
+                events.push(ContactEvent {
+                    ids: *ids,
+                    penetration: response.penetration,
+                });
+            }
+            if let Some(events) = collision_events.as_deref_mut() {
+                events.push(CollisionEvent {
+                    id1: ids[0],
+                    id2: ids[1],
+                    impact_speed: response.approach_speed,
+                    position: response.point,
+                });
+            }
+            if let Some(points) = contact_points.as_deref_mut() {
+                points.push(ContactPoint {
+                    point: response.point,
+                    normal: response.normal,
+                    penetration: response.penetration,
+                });
+            }
+        });
+}
+
+/// Solves every pair in parallel against the same snapshot of the bodies and applies the
+/// sum of the responses of each body
+fn solve_jacobi(
+    positions: &mut [Vec2],
+    velocities: &mut [Vec2],
+    radii: &[f32],
+    bodies: &mut [Body],
+    collisions: &[Collision],
+    friction: f32,
+    wrap: Option<Vec2>,
+) -> Vec<Option<PairResponse>> {
+    // the results keep the pair order so the sums below are always done in the same order
+    let responses: Vec<Option<PairResponse>> = collisions
+        .par_iter()
+        .map(|&Collision([id1, id2])| {
+            let b1 = SolverBody::gather(positions, velocities, radii, bodies, id1);
+            let b2 = SolverBody::gather(positions, velocities, radii, bodies, id2);
+            let p2 = nearest_image(b1.position, b2.position, wrap);
+            contact(b1.position, b1.radius, p2, b2.radius)
+                .map(|contact| collision_response(&b1, &b2, &contact, friction))
+        })
+        .collect();
+
+    let solved = || {
+        collisions
+            .iter()
+            .zip(responses.iter())
+            .filter_map(|(collision, response)| Some((collision, response.as_ref()?)))
+    };
+    let mut contact_counts = vec![0u32; positions.len()];
+    solved().for_each(|(Collision(ids), _)| ids.iter().for_each(|&id| contact_counts[id] += 1));
+
+    // each pair was solved as if it were the only contact, so in a pack the impulses of
+    // all the neighbours overshoot together and feed back into each other. Both bodies of
+    // a pair scale its impulse by the same factor, they stay equal and opposite and the
+    // sums keep the momentum.
+    let mut position_deltas = vec![Vec2::ZERO; positions.len()];
+    let mut velocity_deltas = vec![Vec2::ZERO; positions.len()];
+    let mut angular_deltas = vec![0.0; positions.len()];
+    solved().for_each(|(Collision(ids), response)| {
+        let weight = 1.0 / contact_counts[ids[0]].max(contact_counts[ids[1]]) as f32;
+        ids.iter().enumerate().for_each(|(i, &id)| {
+            position_deltas[id] += response.position[i];
+            velocity_deltas[id] += response.velocity[i] * weight;
+            angular_deltas[id] += response.angular_velocity[i] * weight;
+        });
+    });
+
+    // the pushes apart of all the neighbours would add up too, the total one is capped
+    // like the one of a single pair
+    positions
+        .par_iter_mut()
+        .zip(velocities.par_iter_mut())
+        .zip(bodies.par_iter_mut().zip(radii.par_iter()))
+        .zip(position_deltas.par_iter().zip(velocity_deltas.par_iter()))
+        .zip(angular_deltas.par_iter())
+        .for_each(
+            |((((p, v), (b, &radius)), (position, velocity)), angular)| {
+                *p += position.clamp_length_max(MAX_CORRECTION_FRACTION * radius);
+                *v += *velocity;
+                b.angular_velocity += angular;
+                if velocity.length_squared() > SLEEP_SPEED * SLEEP_SPEED {
+                    b.wake();
+                }
+            },
+        );
+
+    responses
+}
+
+/// Copy of the components of a body the sequential resolvers read and write, so the regions
+/// can be solved on their own copies
+#[derive(Clone)]
+struct SolverBody {
+    position: Vec2,
+    velocity: Vec2,
+    angular_velocity: f32,
+    radius: f32,
+    mass: f32,
+    restitution: f32,
+    /// A response was strong enough to wake the body
+    woken: bool,
+}
+
+impl SolverBody {
+    fn gather(
+        positions: &[Vec2],
+        velocities: &[Vec2],
+        radii: &[f32],
+        bodies: &[Body],
+        id: usize,
+    ) -> Self {
+        let b = &bodies[id];
+        Self {
+            position: positions[id],
+            velocity: velocities[id],
+            angular_velocity: b.angular_velocity,
+            radius: radii[id],
+            mass: b.mass,
+            restitution: b.restitution,
+            woken: false,
+        }
+    }
+
+    /// Writes the solved components back to the body `id`
+    fn scatter(
+        self,
+        positions: &mut [Vec2],
+        velocities: &mut [Vec2],
+        bodies: &mut [Body],
+        id: usize,
+    ) {
+        positions[id] = self.position;
+        velocities[id] = self.velocity;
+        bodies[id].angular_velocity = self.angular_velocity;
+        if self.woken {
+            bodies[id].wake();
+        }
+    }
+}
+
+/// Solves the pairs one after another, each against the bodies as the previous ones left
+/// them. The pairs go in the order given by `region_sets`, with `parallel` the regions of
+/// each set are solved at the same time, which gives the same result as they share no
+/// bodies.
+fn solve_regions(
+    bodies: &mut [SolverBody],
+    collisions: &[Collision],
+    friction: f32,
+    wrap: Option<Vec2>,
+    parallel: bool,
+) -> Vec<Option<PairResponse>> {
+    let mut responses: Vec<Option<PairResponse>> = collisions.iter().map(|_| None).collect();
+    region_sets(bodies, collisions, wrap)
+        .into_iter()
+        .for_each(|regions| {
+            if !parallel {
+                regions.iter().flatten().for_each(|&pair| {
+                    let Collision([id1, id2]) = collisions[pair];
+                    responses[pair] = solve_pair(bodies, id1, id2, friction, wrap);
+                });
+                return;
+            }
+
+            // each region is solved on a copy of its bodies, written back afterwards
+            let solved: Vec<_> = regions
+                .par_iter()
+                .map(|pairs| {
+                    let mut ids: Vec<usize> =
+                        pairs.iter().flat_map(|&pair| collisions[pair].0).collect();
+                    ids.sort_unstable();
+                    ids.dedup();
+                    let mut local: Vec<SolverBody> =
+                        ids.iter().map(|&id| bodies[id].clone()).collect();
+                    // sorted, so the local ids keep the lowest id first
+                    let local_id = |id| ids.binary_search(&id).unwrap();
+                    let pair_responses: Vec<_> = pairs
+                        .iter()
+                        .map(|&pair| {
+                            let Collision([id1, id2]) = collisions[pair];
+                            solve_pair(&mut local, local_id(id1), local_id(id2), friction, wrap)
+                        })
+                        .collect();
+                    (ids, local, pair_responses)
+                })
+                .collect();
+            regions
+                .iter()
+                .zip(solved)
+                .for_each(|(pairs, (ids, local, pair_responses))| {
+                    ids.into_iter()
+                        .zip(local)
+                        .for_each(|(id, body)| bodies[id] = body);
+                    pairs
+                        .iter()
+                        .zip(pair_responses)
+                        .for_each(|(&pair, response)| responses[pair] = response);
+                });
+        });
+    responses
+}
+
+/// Pair indices grouped by the region of the middle point between the two bodies, in four
+/// sets of regions laid out like a checkerboard of 2x2 tiles. The middle point is within
+/// the largest radius of both bodies, so with regions twice that wide all the pairs of a
+/// body are in one region and its neighbours, and regions in the same set, never
+/// neighbours, never share a body.
+/// Pairs across the edges of a wrapping world have no such middle point, they go to a
+/// fifth set as a single region solved after the rest.
+fn region_sets(
+    bodies: &[SolverBody],
+    collisions: &[Collision],
+    wrap: Option<Vec2>,
+) -> [Vec<Vec<usize>>; 5] {
+    let mut sets: [Vec<Vec<usize>>; 5] = Default::default();
+    let (seam, collisions): (Vec<_>, Vec<_>) =
+        collisions
+            .iter()
+            .enumerate()
+            .partition(|(_, Collision([id1, id2]))| {
+                let p2 = bodies[*id2].position;
+                nearest_image(bodies[*id1].position, p2, wrap) != p2
+            });
+    if !seam.is_empty() {
+        sets[4].push(seam.into_iter().map(|(pair, _)| pair).collect());
+    }
+    if collisions.is_empty() {
+        return sets;
+    }
+
+    let middle =
+        |Collision([id1, id2]): &Collision| (bodies[*id1].position + bodies[*id2].position) * 0.5;
+    let max_radius = bodies.iter().map(|b| b.radius).fold(0.0, f32::max);
+    let side = ISLAND_REGION_SIZE.max(max_radius * 2.0);
+    let (min, max) = collisions
+        .iter()
+        .map(|(_, collision)| middle(collision))
+        .fold(
+            (Vec2::splat(f32::MAX), Vec2::splat(f32::MIN)),
+            |(min, max), point| (min.min(point), max.max(point)),
+        );
+    let cols = ((max.x - min.x) / side) as usize + 1;
+    let rows = ((max.y - min.y) / side) as usize + 1;
+
+    let mut regions = vec![vec![]; cols * rows];
+    collisions.iter().for_each(|&(pair, collision)| {
+        let cell = (middle(collision) - min) / side;
+        regions[cell.y as usize * cols + cell.x as usize].push(pair);
+    });
+    regions
+        .into_iter()
+        .enumerate()
+        .filter(|(_, pairs)| !pairs.is_empty())
+        .for_each(|(i, pairs)| {
+            let (x, y) = (i % cols, i / cols);
+            sets[x % 2 + (y % 2) * 2].push(pairs);
+        });
+    sets
+}
+
+/// Solves the pair against the current state of its bodies and applies the response right
+/// away, `id1` must be lower than `id2`
+fn solve_pair(
+    bodies: &mut [SolverBody],
+    id1: usize,
+    id2: usize,
+    friction: f32,
+    wrap: Option<Vec2>,
+) -> Option<PairResponse> {
+    let (head, tail) = bodies.split_at_mut(id2);
+    let (b1, b2) = (&mut head[id1], &mut tail[0]);
+    let p2 = nearest_image(b1.position, b2.position, wrap);
+    let contact = contact(b1.position, b1.radius, p2, b2.radius)?;
+    let response = collision_response(b1, b2, &contact, friction);
+    [b1, b2].into_iter().enumerate().for_each(|(i, b)| {
+        b.position += response.position[i];
+        b.velocity += response.velocity[i];
+        b.angular_velocity += response.angular_velocity[i];
+        if response.velocity[i].length_squared() > SLEEP_SPEED * SLEEP_SPEED {
+            b.woken = true;
+        }
+    });
+    Some(response)
+}
+
+/// Position and velocity changes for each body of a colliding pair
+struct PairResponse {
+    position: [Vec2; 2],
+    velocity: [Vec2; 2],
+    angular_velocity: [f32; 2],
+    /// Speed the bodies were closing in at, 0.0 if they were already separating
+    approach_speed: f32,
+    penetration: f32,
+    /// Middle of the overlap of the two circles
+    point: Vec2,
+    normal: Vec2,
+}
+
+fn collision_response(
+    b1: &SolverBody,
+    b2: &SolverBody,
+    contact: &Contact,
+    friction: f32,
+) -> PairResponse {
+    let normal = contact.normal;
+    // ignore tiny overlaps and push deep ones out over several steps, summing the
+    // corrections of a crammed pack could launch bodies otherwise
+    let max_correction = MAX_CORRECTION_FRACTION * b1.radius.min(b2.radius);
+    let correction = (contact.penetration - CONTACT_SLOP).clamp(0.0, max_correction);
+    let min_translation_distance = normal * correction;
+
+    // split the penetration by inverse mass, heavier bodies move less
+    let inv_mass1 = 1.0 / b1.mass;
+    let inv_mass2 = 1.0 / b2.mass;
+    let inv_mass_sum = inv_mass1 + inv_mass2;
+    let correction1 = min_translation_distance * (inv_mass1 / inv_mass_sum);
+    let correction2 = min_translation_distance * (inv_mass2 / inv_mass_sum);
+
+    // impulse along the normal, only if the bodies are approaching
+    let relative = b1.velocity - b2.velocity;
+    let relative_vel = relative.dot(normal);
+    let normal_impulse = if relative_vel < 0.0 {
+        let restitution = b1.restitution.min(b2.restitution);
+        normal * (-(1.0 + restitution) * relative_vel / inv_mass_sum)
+    } else {
+        Vec2::ZERO
+    };
+
+    // damp the sliding between the bodies, 0.0 keeps them frictionless
+    let tangent_vel = relative - normal * relative_vel;
+    let friction_impulse = -tangent_vel * (friction / inv_mass_sum);
+    let impulse = normal_impulse + friction_impulse;
+
+    // friction acts on the surface so it also spins the bodies, both are solid discs with
+    // an inertia of `mass * radius² / 2` and the contact at `radius` along the normal
+    let torque_arm = normal.perp_dot(friction_impulse);
+    let spin1 = -2.0 * torque_arm / (b1.mass * b1.radius);
+    let spin2 = -2.0 * torque_arm / (b2.mass * b2.radius);
+
+    PairResponse {
+        position: [correction1, -correction2],
+        velocity: [impulse * inv_mass1, -impulse * inv_mass2],
+        angular_velocity: [spin1, spin2],
+        approach_speed: (-relative_vel).max(0.0),
+        penetration: contact.penetration,
+        point: b1.position - normal * (b1.radius - contact.penetration * 0.5),
+        normal,
+    }
+}
+
+/// The four edges of the `bounds` as rects outside of it. The left and right walls cover the
+/// corners and the top and bottom ones stop at them, so a body in a corner touches a flat
+/// side of each and is pushed once along each axis, never diagonally by a rect corner.
+fn arena_walls(bounds: &Rect) -> [Rect; 4] {
+    let t = ARENA_WALL_THICKNESS;
+    [
+        Rect {
+            x: bounds.min_x() - t,
+            y: bounds.min_y() - t,
+            width: t,
+            height: bounds.height + t * 2.0,
+        },
+        Rect {
+            x: bounds.max_x(),
+            y: bounds.min_y() - t,
+            width: t,
+            height: bounds.height + t * 2.0,
+        },
+        Rect {
+            x: bounds.min_x(),
+            y: bounds.min_y() - t,
+            width: bounds.width,
+            height: t,
+        },
+        Rect {
+            x: bounds.min_x(),
+            y: bounds.max_y(),
+            width: bounds.width,
+            height: t,
+        },
+    ]
+}
+
+/// `restitution` scales the reflected velocity, 0.0 makes bodies slide along the walls.
+/// Only a velocity pointing out of the bounds is reflected, so a body left on the edge
+/// by an earlier call isn't sent back into the wall
+fn bounce_body(
+    position: &mut Vec2,
+    velocity: &mut Vec2,
+    radius: f32,
+    bounds: &Rect,
+    restitution: f32,
+) {
+    let min = vec2(bounds.min_x(), bounds.min_y()) + radius;
+    let max = vec2(bounds.max_x(), bounds.max_y()) - radius;
+    if position.x <= min.x {
+        if velocity.x < 0.0 {
+            velocity.x *= -restitution;
+        }
+        position.x = reflect_overshoot(position.x, min.x, restitution);
+    }
+    if position.x >= max.x {
+        if velocity.x > 0.0 {
+            velocity.x *= -restitution;
+        }
+        position.x = reflect_overshoot(position.x, max.x, restitution);
+    }
+    if position.y <= min.y {
+        if velocity.y < 0.0 {
+            velocity.y *= -restitution;
+        }
+        position.y = reflect_overshoot(position.y, min.y, restitution);
+    }
+    if position.y >= max.y {
+        if velocity.y > 0.0 {
+            velocity.y *= -restitution;
+        }
+        position.y = reflect_overshoot(position.y, max.y, restitution);
+    }
+
+    // an overshoot longer than the arena can still end outside after reflecting
+    position.x = position.x.max(min.x).min(max.x);
+    position.y = position.y.max(min.y).min(max.y);
+}
+
+fn wrap_body(position: &mut Vec2, last_position: &mut Vec2, size: Vec2) {
+    let wrapped = vec2(position.x.rem_euclid(size.x), position.y.rem_euclid(size.y));
+    // move the previous position too so the interpolation doesn't cross the world
+    *last_position += wrapped - *position;
+    *position = wrapped;
+}
+
+/// Mirrors the overshoot past `limit` along one axis, scaled by the restitution like the
+/// reflected velocity
+fn reflect_overshoot(position: f32, limit: f32, restitution: f32) -> f32 {
+    limit - (position - limit) * restitution
+}
+
+fn collide_body_static_rects(
+    position: &mut Vec2,
+    velocity: &mut Vec2,
+    radius: f32,
+    obstacles: &[Rect],
+    restitution: f32,
+) {
+    obstacles.iter().for_each(|rect| {
+        if let Some(Contact {
+            normal,
+            penetration,
+        }) = circle_rect_contact(*position, radius, rect)
+        {
+            *position += normal * penetration;
+            let normal_vel = velocity.dot(normal);
+            if normal_vel < 0.0 {
+                *velocity -= normal * normal_vel * (1.0 + restitution);
+            }
+        }
+    });
+}
+
+/// Steers the followers with a force proportional to the difference between the
+/// velocity they have and the one that takes them to the target
+fn sys_follow_mouse(
+    positions: &[Vec2],
+    velocities: &[Vec2],
+    forces: &mut [Vec2],
+    bodies: &[Body],
+    follow_mouse: &[bool],
+    follow_strengths: &[f32],
+    target: Vec2,
+) {
+    forces
+        .iter_mut()
+        .enumerate()
+        .filter(|&(id, _)| follow_mouse[id])
+        .for_each(|(id, force)| {
+            let to_target = target - positions[id];
+            let speed = (to_target.length() * FOLLOW_ARRIVE_GAIN).min(FOLLOW_MAX_SPEED);
+            let desired = to_target.normalize_or_zero() * speed;
+            *force += (desired - velocities[id]) * follow_strengths[id] * bodies[id].mass;
+        });
+}
+
+/// Pushes the followers sideways when the density cell ahead of them is crowded, towards
+/// the emptier of the cells on each side of it
+fn sys_avoid_density(
+    positions: &[Vec2],
+    radii: &[f32],
+    forces: &mut [Vec2],
+    bodies: &[Body],
+    follow_mouse: &[bool],
+    density: &DensityGrid,
+    target: Vec2,
+) {
+    forces
+        .iter_mut()
+        .enumerate()
+        .filter(|&(id, _)| follow_mouse[id])
+        .for_each(|(id, force)| {
+            let position = positions[id];
+            let dir = (target - position).normalize_or_zero();
+            // the cell right past the edge of the follower, along the way to the target
+            let ahead = position + dir * (radii[id] + DENSITY_CELL_SIZE * 0.5);
+            let count = density.count_at(ahead);
+            if count == 0 {
+                return;
+            }
+
+            let side = dir.perp() * DENSITY_CELL_SIZE;
+            let left = density.count_at(ahead + side);
+            let right = density.count_at(ahead - side);
+            let away = if left <= right {
+                dir.perp()
+            } else {
+                -dir.perp()
+            };
+            let amount = (count as f32 / AVOID_FULL_COUNT as f32).min(1.0);
+            *force += away * AVOID_ACCELERATION * amount * bodies[id].mass;
+        });
+}
+
+/// Inverse-square attraction towards `point`, scaled by mass so every body accelerates alike
+fn sys_attract_to_point(
+    positions: &[Vec2],
+    forces: &mut [Vec2],
+    bodies: &[Body],
+    point: Vec2,
+    strength: f32,
+) {
+    forces
+        .iter_mut()
+        .zip(positions.iter().zip(bodies))
+        .for_each(|(force, (&position, b))| {
+            let to_point = point - position;
+            let distance = to_point.length().max(ATTRACT_MIN_DISTANCE);
+            *force += to_point.normalize_or_zero() * (strength / (distance * distance)) * b.mass;
+        });
+}
+
+/// Adds the same velocity to every body whatever its mass, waking the asleep ones so a
+/// settled pile gets shaken too
+fn sys_apply_global_impulse(velocities: &mut [Vec2], bodies: &mut [Body], impulse: Vec2) {
+    velocities.iter_mut().zip(bodies).for_each(|(velocity, b)| {
+        *velocity += impulse;
+        b.wake();
+    });
+}
+
+fn sys_apply_gravity(forces: &mut [Vec2], bodies: &[Body], follow_mouse: &[bool], gravity: Vec2) {
+    forces
+        .iter_mut()
+        .zip(bodies.iter().zip(follow_mouse))
+        .filter(|(_, (b, &follow))| !follow && !b.asleep)
+        .for_each(|(force, (b, _))| {
+            *force += gravity * b.mass;
+        });
+}
+
+/// Forces other than gravity wake asleep bodies up, gravity skips them.
+/// Bodies moving more than their radius in one step move in sub-steps, calling
+/// `constrain` after each one, the only place the walls and obstacles are applied, so
+/// they can't tunnel through them.
+fn sys_apply_movement_to_body(
+    positions: &mut [Vec2],
+    velocities: &mut [Vec2],
+    forces: &mut [Vec2],
+    radii: &[f32],
+    bodies: &mut [Body],
+    delta: f32,
+    constrain: impl Fn(&mut Vec2, &mut Vec2, f32, &mut Body),
+) {
+    positions
+        .iter_mut()
+        .zip(velocities.iter_mut())
+        .zip(forces.iter_mut().zip(radii))
+        .zip(bodies.iter_mut())
+        .for_each(|(((position, velocity), (force, &radius)), b)| {
+            b.last_position = *position;
+            if b.asleep {
+                if *force == Vec2::ZERO {
+                    return;
+                }
+                b.wake();
+            }
+            *velocity += *force / b.mass * delta;
+            *force = Vec2::ZERO;
+
+            let displacement = velocity.length() * delta;
+            let substeps = ((displacement / radius).ceil() as usize).clamp(1, MAX_SUBSTEPS);
+            let substep_delta = delta / substeps as f32;
+            (0..substeps).for_each(|_| {
+                *position += *velocity * substep_delta;
+                constrain(position, velocity, radius, b);
+            });
+        });
+}
+
+fn sys_apply_rotation(bodies: &mut [Body], delta: f32) {
+    bodies.iter_mut().for_each(|b| {
+        b.rotation += b.angular_velocity * delta;
+    });
+}
+
+fn sys_apply_damping(velocities: &mut [Vec2], damping: f32, delta: f32) {
+    let factor = (1.0 - damping * delta).max(0.0);
+    velocities.iter_mut().for_each(|velocity| {
+        *velocity *= factor;
+    });
+}
+
+/// Puts to sleep the bodies slower than `SLEEP_SPEED` for `SLEEP_TIME`. Asleep bodies skip
+/// gravity, so one that no longer touches another body or the static geometry, checked
+/// with `supported`, is woken up to fall.
+#[allow(clippy::too_many_arguments)]
+fn sys_update_sleep(
+    positions: &[Vec2],
+    velocities: &mut [Vec2],
+    radii: &[f32],
+    bodies: &mut [Body],
+    collision_counts: &[u32],
+    delta: f32,
+    supported: impl Fn(Vec2, f32) -> bool,
+) {
+    velocities
+        .iter_mut()
+        .zip(bodies)
+        .zip(positions.iter().zip(radii).zip(collision_counts))
+        .for_each(|((velocity, b), ((&position, &radius), &count))| {
+            if b.asleep {
+                if count == 0 && !supported(position, radius) {
+                    b.wake();
+                }
+                return;
+            }
+            if velocity.length_squared() > SLEEP_SPEED * SLEEP_SPEED {
+                b.sleep_time = 0.0;
+                return;
+            }
+
+            b.sleep_time += delta;
+            if b.sleep_time >= SLEEP_TIME {
+                b.asleep = true;
+                *velocity = Vec2::ZERO;
+                b.angular_velocity = 0.0;
+            }
+        });
+}
+
+/// True if the circle reaches one of the `edges` of the world, when they are solid, or one
+/// of the obstacles, within `CONTACT_SLOP`
+fn touches_static(position: Vec2, radius: f32, edges: Option<&Rect>, obstacles: &[Rect]) -> bool {
+    let reach = radius + CONTACT_SLOP;
+    let on_edge = edges.is_some_and(|edges| {
+        position.x - reach <= edges.min_x()
+            || position.x + reach >= edges.max_x()
+            || position.y - reach <= edges.min_y()
+            || position.y + reach >= edges.max_y()
+    });
+    on_edge
+        || obstacles.iter().any(|rect| {
+            let min = vec2(rect.min_x(), rect.min_y());
+            let max = vec2(rect.max_x(), rect.max_y());
+            position.clamp(min, max).distance_squared(position) <= reach * reach
+        })
+}
+
+/// Counts the bodies per cell of `density` again
+fn sys_density_grid(positions: &[Vec2], density: &mut DensityGrid) {
+    density.counts.fill(0);
+    positions.iter().for_each(|&position| density.add(position));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: Vec2 = vec2(800.0, 600.0);
+    const DELTA: f32 = 1.0 / 120.0;
+
+    /// World without obstacles holding a body per `(position, velocity, radius)`
+    fn world_of(bodies: &[(Vec2, Vec2, f32)]) -> World {
+        let entities = bodies
+            .iter()
+            .map(|&(position, velocity, radius)| create_entity(position, velocity, radius))
+            .collect();
+        World {
+            obstacles: vec![],
+            ..World::new(entities, grid_cell_size(ENTITY_RADIUS), SIZE)
+        }
+    }
+
+    /// World of random entities like the startup scene, obstacles included
+    fn random_world(seed: u64, count: usize) -> World {
+        let mut rng = Random::new(seed);
+        let entities = init_entities(&mut rng, count, 8.0, 24.0, SIZE);
+        World::new(entities, grid_cell_size(24.0), SIZE)
+    }
+
+    const BROADPHASES: [BroadphaseKind; 4] = [
+        BroadphaseKind::BruteForce,
+        BroadphaseKind::Grid,
+        BroadphaseKind::Quadtree,
+        BroadphaseKind::TwoTier,
+    ];
+
+    /// Pairs found by the broadphase of the world and by testing all of them
+    fn broadphase_and_brute_force(world: &mut World) -> (Vec<Collision>, Vec<Collision>) {
+        let wrap = world.wrap();
+        let Entities {
+            positions,
+            radii,
+            bodies,
+            ..
+        } = &world.entities;
+        let stats = &mut BroadphaseStats::default();
+        (
+            sys_check_collision(positions, radii, bodies, &mut world.broadphase, stats, wrap),
+            brute_force_collisions(positions, radii, bodies, wrap),
+        )
+    }
+
+    #[test]
+    fn two_overlapping_circles_are_one_pair() {
+        BROADPHASES.into_iter().for_each(|kind| {
+            let mut world = world_of(&[
+                (vec2(100.0, 100.0), Vec2::ZERO, 10.0),
+                (vec2(112.0, 100.0), Vec2::ZERO, 10.0),
+            ]);
+            world.broadphase.kind = kind;
+            let (found, _) = broadphase_and_brute_force(&mut world);
+            assert_eq!(found, vec![Collision([0, 1])], "{:?}", kind);
+        });
+    }
+
+    #[test]
+    fn every_broadphase_finds_the_brute_force_pairs() {
+        [1, 2, 3].into_iter().for_each(|seed| {
+            [BoundaryMode::Bounce, BoundaryMode::Wrap]
+                .into_iter()
+                .for_each(|boundary| {
+                    BROADPHASES.into_iter().for_each(|kind| {
+                        let mut world = random_world(seed, 400);
+                        // bodies too big for a grid cell go to the coarse grid of `TwoTier`
+                        let mut rng = Random::new(seed);
+                        (0..5).for_each(|_| {
+                            world.spawn(random_entity(&mut rng, 60.0, SIZE));
+                        });
+                        world.boundary = boundary;
+                        world.broadphase.kind = kind;
+                        (0..20).for_each(|_| {
+                            world.step(DELTA);
+                            let (found, expected) = broadphase_and_brute_force(&mut world);
+                            assert!(!expected.is_empty());
+                            assert_eq!(found, expected, "{:?} {:?}", kind, boundary);
+                        });
+                    });
+                });
+        });
+    }
+
+    #[test]
+    fn spawn_returns_the_new_id() {
+        let mut world = world_of(&[(vec2(100.0, 100.0), Vec2::ZERO, 10.0)]);
+        let id = world.spawn(create_entity(vec2(200.0, 100.0), Vec2::ZERO, 10.0));
+        assert_eq!(id, 1);
+        assert_eq!(world.entities.positions[id], vec2(200.0, 100.0));
+    }
+
+    #[test]
+    fn despawn_moves_the_last_entity_into_the_gap() {
+        let mut world = world_of(&[
+            (vec2(100.0, 100.0), Vec2::ZERO, 10.0),
+            (vec2(200.0, 100.0), Vec2::ZERO, 11.0),
+            (vec2(300.0, 100.0), Vec2::ZERO, 12.0),
+        ]);
+        world.despawn(1);
+        assert_eq!(world.entities.len(), 2);
+        assert_eq!(world.entities.positions[1], vec2(300.0, 100.0));
+        assert_eq!(world.entities.radii[1], 12.0);
+    }
+
+    #[test]
+    fn pick_entity_prefers_the_nearest_center() {
+        let mut world = world_of(&[
+            (vec2(100.0, 100.0), Vec2::ZERO, 30.0),
+            (vec2(120.0, 100.0), Vec2::ZERO, 30.0),
+            (vec2(300.0, 100.0), Vec2::ZERO, 2.0),
+        ]);
+        assert_eq!(world.pick_entity(vec2(105.0, 100.0)), Some(0));
+        assert_eq!(world.pick_entity(vec2(115.0, 100.0)), Some(1));
+        // small bodies are picked within `MIN_PICK_RADIUS`
+        assert_eq!(world.pick_entity(vec2(306.0, 100.0)), Some(2));
+    }
+
+    #[test]
+    fn pick_entity_finds_nothing_on_empty_space() {
+        let mut world = world_of(&[(vec2(100.0, 100.0), Vec2::ZERO, 30.0)]);
+        assert_eq!(world.pick_entity(vec2(400.0, 400.0)), None);
+        assert_eq!(world.pick_entity(vec2(125.0, 125.0)), None);
+    }
+
+    #[test]
+    fn resize_changes_the_mass_with_the_radius() {
+        let mut world = world_of(&[(vec2(100.0, 100.0), Vec2::ZERO, 10.0)]);
+        world.resize(0, 20.0);
+        assert_eq!(world.entities.radii[0], 20.0);
+        assert_eq!(world.entities.bodies[0].mass, mass_from_radius(20.0));
+    }
+
+    #[test]
+    fn query_region_returns_the_overlapping_bodies() {
+        let mut world = world_of(&[
+            (vec2(100.0, 100.0), Vec2::ZERO, 10.0),
+            (vec2(400.0, 300.0), Vec2::ZERO, 10.0),
+            (vec2(700.0, 500.0), Vec2::ZERO, 10.0),
+        ]);
+        let found = world.query_region(vec2(380.0, 280.0), vec2(800.0, 600.0));
+        assert_eq!(found, vec![1, 2]);
+    }
+
+    #[test]
+    fn is_crowded_needs_a_deep_overlap() {
+        let mut world = world_of(&[
+            (vec2(100.0, 100.0), Vec2::ZERO, 10.0),
+            (vec2(119.0, 100.0), Vec2::ZERO, 10.0),
+        ]);
+        assert!(!world.is_crowded(0));
+        world.entities.positions[1].x = 110.0;
+        assert!(world.is_crowded(0));
+    }
+
+    #[test]
+    fn layers_that_dont_accept_each_other_never_pair() {
+        let mut world = world_of(&[
+            (vec2(100.0, 100.0), Vec2::ZERO, 10.0),
+            (vec2(105.0, 100.0), Vec2::ZERO, 10.0),
+        ]);
+        let pairs = |world: &mut World| {
+            let Entities {
+                positions,
+                radii,
+                bodies,
+                ..
+            } = &world.entities;
+            let stats = &mut BroadphaseStats::default();
+            sys_check_collision(positions, radii, bodies, &mut world.broadphase, stats, None)
+        };
+        assert_eq!(pairs(&mut world).len(), 1);
+
+        let bodies = &mut world.entities.bodies;
+        (bodies[0].layer, bodies[0].mask) = (0b01, 0b01);
+        (bodies[1].layer, bodies[1].mask) = (0b10, 0b10);
+        assert!(pairs(&mut world).is_empty());
+
+        // it takes both masks, one side accepting the other isn't enough
+        world.entities.bodies[0].mask = 0b11;
+        assert!(pairs(&mut world).is_empty());
+    }
+
+    #[test]
+    fn nothing_sleeps_by_default() {
+        let mut world = world_of(&[(vec2(400.0, 300.0), Vec2::ZERO, 10.0)]);
+        (0..240).for_each(|_| world.step(DELTA));
+        assert!(!world.entities.bodies[0].asleep);
+    }
+
+    #[test]
+    fn sleepers_wake_up_when_nothing_holds_them() {
+        let mut world = world_of(&[
+            (vec2(400.0, 300.0), Vec2::ZERO, 10.0),
+            (vec2(200.0, 0.0), Vec2::ZERO, 10.0),
+        ]);
+        let floor = world.bounds().max_y();
+        world.entities.positions[1].y = floor - 10.0;
+        world.gravity = vec2(0.0, 980.0);
+        world.sleep = true;
+        world
+            .entities
+            .bodies
+            .iter_mut()
+            .for_each(|b| b.asleep = true);
+
+        (0..10).for_each(|_| world.step(DELTA));
+        // the one in the air falls, the one on the floor keeps sleeping
+        assert!(!world.entities.bodies[0].asleep);
+        assert!(world.entities.positions[0].y > 300.0);
+        assert!(world.entities.bodies[1].asleep);
+    }
+
+    /// Bodies piled on almost the same point, each one overlapping all the others
+    fn heavy_overlap(count: usize) -> World {
+        let mut rng = Random::new(11);
+        let bodies: Vec<_> = (0..count)
+            .map(|_| {
+                let offset = vec2(rng.gen::<f32>(), rng.gen::<f32>()) * 4.0;
+                let velocity = vec2(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5) * 400.0;
+                (vec2(400.0, 300.0) + offset, velocity, 10.0)
+            })
+            .collect();
+        world_of(&bodies)
+    }
+
+    /// Runs the Jacobi resolver once on every overlapping pair of the world
+    fn jacobi_pass(world: &mut World, friction: f32) {
+        let Entities {
+            positions,
+            velocities,
+            radii,
+            bodies,
+            ..
+        } = &mut world.entities;
+        let collisions = brute_force_collisions(positions, radii, bodies, None);
+        solve_jacobi(
+            positions,
+            velocities,
+            radii,
+            bodies,
+            &collisions,
+            friction,
+            None,
+        );
+    }
+
+    #[test]
+    fn equal_bodies_each_move_half_the_correction() {
+        // a shallow overlap is corrected minus the slop, a deep one up to the clamp
+        [
+            (1.0, 1.0 - CONTACT_SLOP),
+            (4.0, MAX_CORRECTION_FRACTION * 10.0),
+        ]
+        .into_iter()
+        .for_each(|(penetration, correction)| {
+            let distance = 20.0 - penetration;
+            let mut world = world_of(&[
+                (vec2(100.0, 100.0), Vec2::ZERO, 10.0),
+                (vec2(100.0 + distance, 100.0), Vec2::ZERO, 10.0),
+            ]);
+            jacobi_pass(&mut world, 0.0);
+            let positions = &world.entities.positions;
+            assert!((positions[0].x - (100.0 - correction * 0.5)).abs() < 1e-4);
+            assert!((positions[1].x - (100.0 + distance + correction * 0.5)).abs() < 1e-4);
+        });
+    }
+
+    #[test]
+    fn coincident_bodies_come_apart() {
+        [Resolver::Jacobi, Resolver::Islands]
+            .into_iter()
+            .for_each(|resolver| {
+                let mut world = world_of(&[
+                    (vec2(400.0, 300.0), Vec2::ZERO, 10.0),
+                    (vec2(400.0, 300.0), Vec2::ZERO, 10.0),
+                ]);
+                world.resolver = resolver;
+                // the correction is clamped per step, a full overlap takes a few of them
+                (0..30).for_each(|_| world.step(DELTA));
+                let positions = &world.entities.positions;
+                let distance = positions[0].distance(positions[1]);
+                assert!(distance >= 20.0 - CONTACT_SLOP - 1e-3, "{:?}", resolver);
+            });
+    }
+
+    #[test]
+    fn jacobi_correction_stays_bounded_under_heavy_overlap() {
+        let mut world = heavy_overlap(50);
+        let before = world.entities.positions.clone();
+        jacobi_pass(&mut world, 0.0);
+        world
+            .entities
+            .positions
+            .iter()
+            .zip(&before)
+            .zip(&world.entities.radii)
+            .for_each(|((&after, &before), &radius)| {
+                assert!(after.distance(before) <= MAX_CORRECTION_FRACTION * radius + 1e-4);
+            });
+    }
+
+    #[test]
+    fn jacobi_keeps_the_momentum() {
+        let mut world = heavy_overlap(50);
+        let momentum = |world: &World| {
+            world
+                .entities
+                .velocities
+                .iter()
+                .zip(&world.entities.bodies)
+                .map(|(&velocity, b)| velocity * b.mass)
+                .sum::<Vec2>()
+        };
+        let before = momentum(&world);
+        jacobi_pass(&mut world, 0.3);
+        let total_mass: f32 = world.entities.bodies.iter().map(|b| b.mass).sum();
+        assert!(momentum(&world).distance(before) / total_mass < 1e-3);
+    }
+
+    #[test]
+    fn kept_index_finds_the_same_pairs_as_a_fresh_one() {
+        BROADPHASES.into_iter().for_each(|kind| {
+            let mut world = random_world(4, 400);
+            world.broadphase.kind = kind;
+            (0..60).for_each(|_| {
+                world.step(DELTA);
+                let (kept, _) = broadphase_and_brute_force(&mut world);
+                let mut fresh = Broadphase::new(kind, grid_cell_size(24.0), SIZE);
+                let Entities {
+                    positions,
+                    radii,
+                    bodies,
+                    ..
+                } = &world.entities;
+                let stats = &mut BroadphaseStats::default();
+                let expected =
+                    sys_check_collision(positions, radii, bodies, &mut fresh, stats, None);
+                assert_eq!(kept, expected, "{:?}", kind);
+            });
+        });
+    }
+
+    #[test]
+    fn every_resolver_is_deterministic_whatever_the_threads() {
+        let pool = |threads| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+        };
+        let (single, many) = (pool(1), pool(4));
+        let hashes: Vec<u64> = [Resolver::Jacobi, Resolver::Islands, Resolver::Sequential]
+            .into_iter()
+            .map(|resolver| {
+                let hash = |pool: &rayon::ThreadPool| {
+                    let mut world = random_world(5, 300);
+                    world.resolver = resolver;
+                    world.resolve_iterations = 2;
+                    pool.install(|| (0..100).for_each(|_| world.step(DELTA)));
+                    world.state_hash()
+                };
+                let expected = hash(&single);
+                assert_eq!(hash(&single), expected, "{:?}", resolver);
+                assert_eq!(hash(&many), expected, "{:?}", resolver);
+                expected
+            })
+            .collect();
+        // the sequential resolver is the islands one on a single thread
+        assert_eq!(hashes[1], hashes[2]);
+    }
+
+    #[test]
+    fn jacobi_matches_sequential_with_one_contact_per_body() {
+        // pairs far apart from each other, each body only ever touches its partner
+        let pairs: Vec<(Vec2, Vec2, f32)> = (0..6)
+            .flat_map(|i| {
+                let center = vec2(
+                    100.0 + (i % 3) as f32 * 250.0,
+                    150.0 + (i / 3) as f32 * 300.0,
+                );
+                let offset = vec2(8.0 + i as f32 * 0.5, i as f32 - 3.0);
+                [
+                    (center - offset, vec2(40.0, 10.0 * i as f32), 10.0),
+                    (center + offset, vec2(-30.0, -5.0), 10.0 + i as f32),
+                ]
+            })
+            .collect();
+        let run = |resolver| {
+            let mut world = world_of(&pairs);
+            world.resolver = resolver;
+            world.step(DELTA);
+            assert!(world
+                .entities
+                .collision_counts
+                .iter()
+                .all(|&count| count == 1));
+            (0..20).for_each(|_| world.step(DELTA));
+            world
+        };
+        let (jacobi, sequential) = (run(Resolver::Jacobi), run(Resolver::Sequential));
+        let close = |a: &[Vec2], b: &[Vec2]| a.iter().zip(b).all(|(a, b)| a.abs_diff_eq(*b, 1e-3));
+        assert!(close(
+            &jacobi.entities.positions,
+            &sequential.entities.positions
+        ));
+        assert!(close(
+            &jacobi.entities.velocities,
+            &sequential.entities.velocities
+        ));
+    }
+
+    #[test]
+    fn broken_bodies_are_put_back_inside() {
+        let mut world = random_world(9, 100);
+        world.entities.positions[3] = vec2(f32::NAN, 100.0);
+        world.entities.velocities[7] = vec2(f32::INFINITY, 0.0);
+        world.step(DELTA);
+        assert!(world.reset_count > 0);
+        (0..5).for_each(|_| world.step(DELTA));
+        assert_eq!(world.reset_count, 0);
+        world
+            .entities
+            .positions
+            .iter()
+            .zip(&world.entities.velocities)
+            .for_each(|(&position, &velocity)| {
+                assert!(position.is_finite() && velocity.is_finite());
+                assert!(position.cmpge(Vec2::ZERO).all() && position.cmple(SIZE).all());
+            });
+    }
+
+    #[test]
+    fn damping_only_slows_down() {
+        let mut world = world_of(&[(vec2(400.0, 300.0), vec2(300.0, 200.0), 10.0)]);
+        world.linear_damping = 0.5;
+        let mut speed = world.entities.velocities[0].length();
+        (0..600).for_each(|_| {
+            world.step(DELTA);
+            let new_speed = world.entities.velocities[0].length();
+            assert!(new_speed < speed);
+            speed = new_speed;
+        });
+    }
+
+    #[test]
+    fn huge_steps_never_leave_the_bounds() {
+        [BoundaryMode::Bounce, BoundaryMode::Walls]
+            .into_iter()
+            .for_each(|boundary| {
+                // several world widths per step
+                let velocity = vec2(300_000.0, -170_000.0);
+                let mut world = world_of(&[(vec2(400.0, 300.0), velocity, 10.0)]);
+                world.boundary = boundary;
+                let bounds = world.bounds();
+                (0..60).for_each(|_| {
+                    world.step(DELTA);
+                    let position = world.entities.positions[0];
+                    assert!(
+                        position.x >= bounds.min_x() + 10.0 - 0.01
+                            && position.x <= bounds.max_x() - 10.0 + 0.01
+                            && position.y >= bounds.min_y() + 10.0 - 0.01
+                            && position.y <= bounds.max_y() - 10.0 + 0.01,
+                        "{:?} {}",
+                        boundary,
+                        position
+                    );
+                });
+            });
+    }
+
+    #[test]
+    fn fast_bodies_dont_tunnel_through_obstacles() {
+        let radius = 4.0;
+        let mut world = world_of(&[(vec2(100.0, 300.0), vec2(3000.0, 0.0), radius)]);
+        let obstacle = Rect {
+            x: 400.0,
+            y: 200.0,
+            width: 10.0,
+            height: 200.0,
+        };
+        world.obstacles = vec![obstacle];
+        (0..240).for_each(|_| {
+            world.step(DELTA);
+            let position = world.entities.positions[0];
+            assert!(
+                position.x + radius <= obstacle.min_x() + 0.01,
+                "{}",
+                position
+            );
+        });
+    }
+
+    #[test]
+    fn bodies_exactly_on_an_edge_bounce_back() {
+        let bounds = Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 800.0,
+            height: 600.0,
+        };
+        let radius = 10.0;
+        [
+            (vec2(10.0, 300.0), vec2(-100.0, 20.0)),
+            (vec2(790.0, 300.0), vec2(100.0, 20.0)),
+            (vec2(400.0, 10.0), vec2(20.0, -100.0)),
+            (vec2(400.0, 590.0), vec2(20.0, 100.0)),
+        ]
+        .into_iter()
+        .for_each(|(start, outward)| {
+            let (mut position, mut velocity) = (start, outward);
+            bounce_body(&mut position, &mut velocity, radius, &bounds, 0.5);
+            assert_eq!(position, start);
+            if outward.x.abs() > outward.y.abs() {
+                assert_eq!(velocity, vec2(-outward.x * 0.5, outward.y));
+            } else {
+                assert_eq!(velocity, vec2(outward.x, -outward.y * 0.5));
+            }
+        });
+    }
+
+    #[test]
+    fn bouncing_twice_leaves_the_velocity_alone() {
+        let bounds = Rect {
+            x: 0.0,
+            y: 0.0,
+            width: 800.0,
+            height: 600.0,
+        };
+        let (mut position, mut velocity) = (vec2(10.0, 300.0), vec2(-100.0, 0.0));
+        bounce_body(&mut position, &mut velocity, 10.0, &bounds, 0.5);
+        let (bounced_position, bounced_velocity) = (position, velocity);
+        bounce_body(&mut position, &mut velocity, 10.0, &bounds, 0.5);
+        assert_eq!(velocity, bounced_velocity);
+        assert_eq!(position, bounced_position);
+    }
+
+    #[test]
+    fn steps_keep_the_bodies_inside_the_bounds() {
+        let mut world = random_world(3, 300);
+        (0..200).for_each(|_| world.step(DELTA));
+        let bounds = world.bounds();
+        world
+            .entities
+            .positions
+            .iter()
+            .zip(&world.entities.radii)
+            .for_each(|(&position, &radius)| {
+                // the collisions are solved after the edges, a pack can push a body past
+                // them by one capped correction until the next step
+                let inset = radius * (1.0 - MAX_CORRECTION_FRACTION);
+                assert!(position.x >= bounds.min_x() + inset);
+                assert!(position.x <= bounds.max_x() - inset);
+                assert!(position.y >= bounds.min_y() + inset);
+                assert!(position.y <= bounds.max_y() - inset);
+            });
+    }
+
+    #[test]
+    fn energy_sums_every_body() {
+        let world = world_of(&[
+            (vec2(100.0, 100.0), vec2(10.0, 0.0), 10.0),
+            (vec2(300.0, 100.0), vec2(-10.0, 0.0), 10.0),
+        ]);
+        let (energy, momentum) = world.energy();
+        assert_eq!(energy, mass_from_radius(10.0) * 100.0);
+        assert_eq!(momentum, 0.0);
+    }
+
+    #[test]
+    fn touching_circles_have_no_penetration() {
+        let contact = contact(vec2(20.0, 0.0), 10.0, Vec2::ZERO, 10.0).unwrap();
+        assert_eq!(contact.penetration, 0.0);
+        assert_eq!(contact.normal, Vec2::X);
+        assert!(super::contact(vec2(20.1, 0.0), 10.0, Vec2::ZERO, 10.0).is_none());
+    }
+
+    #[test]
+    fn deep_overlap_pushes_the_first_circle_away() {
+        let contact = contact(vec2(0.0, 3.0), 10.0, vec2(0.0, 8.0), 5.0).unwrap();
+        assert_eq!(contact.penetration, 10.0);
+        assert_eq!(contact.normal, -Vec2::Y);
+    }
+
+    #[test]
+    fn coincident_centers_push_along_x() {
+        let contact = contact(vec2(5.0, 5.0), 10.0, vec2(5.0, 5.0), 4.0).unwrap();
+        assert_eq!(contact.penetration, 14.0);
+        assert_eq!(contact.normal, Vec2::X);
+    }
+}